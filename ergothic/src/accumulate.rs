@@ -66,6 +66,13 @@ impl Acc {
   /// `self.consume(..)` for each of the samples consumed previously by `other`.
   /// Destructs `other` upon completion.
   pub fn merge(&mut self, mut other: Acc) {
+    if other.count == 0.0 {
+      return;
+    }
+    if self.count == 0.0 {
+      *self = other;
+      return;
+    }
     let total_count = self.count + other.count;
     self.mean -= self.mean * (other.count / total_count);
     other.mean -= other.mean * (self.count / total_count);
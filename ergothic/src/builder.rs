@@ -0,0 +1,230 @@
+use ::equilibrate::EquilibrationSettings;
+use ::export::DebugExporter;
+use ::export::Exporter;
+use ::measure::MeasureRegistry;
+use ::panics::PanicPolicy;
+use ::simulation::Parameters;
+use ::stop::Results;
+use ::stop::StopCriteria;
+use ::stop::Uncertainty;
+use ::stop::UncertaintyTarget;
+use ::std::time::Duration;
+
+/// Configures the simulation engine programmatically, as an alternative to
+/// parsing the command line. Use it to embed a simulation in a larger program
+/// or in a test. Pass it to `Simulation::configure(..)` before running the
+/// simulation.
+pub struct SimulationBuilder {
+  exporter: Box<dyn Exporter + Send>,
+  export_queue_len: usize,
+  flush_interval: Duration,
+  max_export_errors_in_row: Option<usize>,
+  threads: usize,
+  stop: StopCriteria,
+  handle_signals: bool,
+  shutdown_grace_period: Duration,
+  equilibration: Option<EquilibrationSettings>,
+  on_panic: Option<PanicPolicy>,
+  params: Option<::serde_json::Value>,
+  seed: Option<u64>,
+  node_index: u64,
+}
+
+impl Default for SimulationBuilder {
+  fn default() -> SimulationBuilder {
+    SimulationBuilder::new()
+  }
+}
+
+impl SimulationBuilder {
+  /// Constructs a builder with the defaults of the development mode: the
+  /// values are printed to stdout every 2 seconds, a single chain is run, and
+  /// the simulation never stops. Unlike with the command line, signal
+  /// handlers are not installed.
+  pub fn new() -> SimulationBuilder {
+    SimulationBuilder {
+      exporter: Box::new(DebugExporter::new()),
+      export_queue_len: 2,
+      flush_interval: Duration::from_secs(2),
+      max_export_errors_in_row: None,
+      threads: 1,
+      stop: StopCriteria::default(),
+      handle_signals: false,
+      shutdown_grace_period: Duration::from_secs(20),
+      equilibration: None,
+      on_panic: None,
+      params: None,
+      seed: None,
+      node_index: 0,
+    }
+  }
+
+  /// Sets the exporter the accumulated values are sent to. The exporter runs
+  /// in a background thread.
+  pub fn exporter<E: Exporter + Send + 'static>(mut self, exporter: E)
+         -> SimulationBuilder {
+    self.exporter = Box::new(exporter);
+    self
+  }
+
+  /// Sets the maximum number of data points waiting to be exported. Values
+  /// flushed while the queue is full are merged into the last waiting data
+  /// point. The default is 2.
+  pub fn export_queue_len(mut self, len: usize) -> SimulationBuilder {
+    assert!(len > 0, "The export queue should hold at least one data point.");
+    self.export_queue_len = len;
+    self
+  }
+
+  /// Sets the interval between subsequent flushes of the accumulated values.
+  pub fn flush_interval(mut self, interval: Duration) -> SimulationBuilder {
+    self.flush_interval = interval;
+    self
+  }
+
+  /// Panic after this many export errors in a row. By default, the simulation
+  /// never gives up on exporting.
+  pub fn max_export_errors_in_row(mut self, errors: usize)
+         -> SimulationBuilder {
+    self.max_export_errors_in_row = Some(errors);
+    self
+  }
+
+  /// Sets the number of independent chains to run in separate threads.
+  pub fn threads(mut self, threads: usize) -> SimulationBuilder {
+    assert!(threads > 0, "At least one thread is required.");
+    self.threads = threads;
+    self
+  }
+
+  /// Stop the simulation after drawing this many samples over all chains.
+  pub fn max_samples(mut self, samples: usize) -> SimulationBuilder {
+    self.stop.max_samples = Some(samples);
+    self
+  }
+
+  /// Stop the simulation after running for this long.
+  pub fn max_duration(mut self, duration: Duration) -> SimulationBuilder {
+    self.stop.max_duration = Some(duration);
+    self
+  }
+
+  /// Stop the simulation once the measure `name` reaches the absolute
+  /// statistical `uncertainty`, and all other targets are reached as well.
+  pub fn target_uncertainty<N: ToString>(mut self, name: N, uncertainty: f64)
+         -> SimulationBuilder {
+    self.stop.targets.push(UncertaintyTarget {
+      measure: name.to_string(),
+      uncertainty: Uncertainty::Absolute(uncertainty),
+    });
+    self
+  }
+
+  /// Same as `target_uncertainty(..)`, but the uncertainty is relative to the
+  /// absolute value of the expectation.
+  pub fn target_relative_uncertainty<N: ToString>(mut self, name: N,
+                                                  uncertainty: f64)
+         -> SimulationBuilder {
+    self.stop.targets.push(UncertaintyTarget {
+      measure: name.to_string(),
+      uncertainty: Uncertainty::Relative(uncertainty),
+    });
+    self
+  }
+
+  /// Install handlers for SIGINT and SIGTERM, which stop the simulation
+  /// gracefully. Disabled by default, since the embedding program usually
+  /// handles the signals by itself.
+  pub fn handle_signals(mut self, handle_signals: bool) -> SimulationBuilder {
+    self.handle_signals = handle_signals;
+    self
+  }
+
+  /// When the simulation stops, retry failed attempts to flush the remaining
+  /// values for this long. The default is 20 seconds.
+  pub fn shutdown_grace_period(mut self, period: Duration)
+         -> SimulationBuilder {
+    self.shutdown_grace_period = period;
+    self
+  }
+
+  /// Enables the adaptive thermalization, which checks the tracked values for
+  /// stationarity after `min_mutations` and gives up after `max_mutations`.
+  pub fn adaptive_thermalization(mut self, min_mutations: usize,
+                                 max_mutations: usize) -> SimulationBuilder {
+    self.equilibration = Some(EquilibrationSettings {
+      min_mutations,
+      max_mutations,
+    });
+    self
+  }
+
+  /// Catches panics in the sample and in the measurement function, discarding
+  /// the offending sample and proceeding according to `policy`. By default,
+  /// panics are not caught. With `PanicPolicy::Exit`, the simulation stops
+  /// with `StopReason::Panicked`.
+  pub fn on_panic(mut self, policy: PanicPolicy) -> SimulationBuilder {
+    self.on_panic = Some(policy);
+    self
+  }
+
+  /// Sets the parameters the samples are prepared with. Panics if they can't
+  /// be serialized.
+  pub fn params<P: ::serde::Serialize>(mut self, params: &P)
+         -> SimulationBuilder {
+    self.params = Some(::serde_json::to_value(params).unwrap_or_else(
+      |err| panic!("Failed to serialize the parameters: {}", err)));
+    self
+  }
+
+  /// Sets the seed of the random number generators, making the simulation
+  /// reproducible. By default, a random seed is drawn.
+  pub fn seed(mut self, seed: u64) -> SimulationBuilder {
+    assert!(seed <= ::rng::MAX_SEED, "The seed should be less than 2^63.");
+    self.seed = Some(seed);
+    self
+  }
+
+  /// Sets the index of the node, which distinguishes the streams of random
+  /// numbers of simulations running with the same seed. Should be less than
+  /// 2^63.
+  pub fn node_index(mut self, node_index: u64) -> SimulationBuilder {
+    assert!(node_index <= ::export::MAX_NODE_INDEX,
+            "The node index should be less than 2^63.");
+    self.node_index = node_index;
+    self
+  }
+}
+
+/// Runs the simulation `engine` once with the parameters given by the
+/// `builder`. Unlike `startup::launch(..)`, never exits the process: the
+/// caller should check `Results::flushed`.
+pub fn launch<E>(builder: SimulationBuilder, name: &str, reg: MeasureRegistry,
+                 mutations_per_measurement: usize, mut engine: E) -> Results
+  where E: FnMut(Parameters) -> Results {
+  let metadata = ::export::Metadata {
+    seed: builder.seed.unwrap_or_else(::rng::random_seed),
+    node_index: builder.node_index,
+    mutations_per_measurement,
+    params: ::serde_json::Value::Null,
+    sweep_point: None,
+  };
+  engine(Parameters {
+    name: name.to_string(),
+    measures: reg.freeze(),
+    exporter: Some(builder.exporter),
+    export_queue_len: builder.export_queue_len,
+    flush_interval: builder.flush_interval,
+    max_export_errors_in_row: builder.max_export_errors_in_row,
+    threads: builder.threads,
+    stop: builder.stop,
+    handle_signals: builder.handle_signals,
+    shutdown_grace_period: builder.shutdown_grace_period,
+    equilibration: builder.equilibration,
+    refresh: None,
+    on_panic: builder.on_panic,
+    params: builder.params,
+    metadata,
+    checkpoint: None,
+  })
+}
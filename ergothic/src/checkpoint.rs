@@ -0,0 +1,403 @@
+use ::measure::Measures;
+use ::rng::Rng;
+use ::serde::Serialize;
+use ::serde::de::DeserializeOwned;
+use ::std::path::PathBuf;
+use ::std::time::Duration;
+use ::std::time::Instant;
+
+/// Where and how often the state of the simulation is checkpointed.
+#[derive(Clone, Debug)]
+pub struct CheckpointSettings {
+  /// Path to the checkpoint file. The file is replaced atomically, so it
+  /// always contains a complete checkpoint.
+  pub path: PathBuf,
+
+  /// Minimal interval between subsequent checkpoints. Checkpoints are only
+  /// written right after flushing the accumulated values.
+  pub interval: Duration,
+
+  /// Resume from the checkpoint file, if it exists, instead of preparing and
+  /// thermalizing new samples.
+  pub resume: bool,
+}
+
+/// The state of the simulation saved in a checkpoint file.
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+  /// Thermalized samples, one per chain, serialized with `Codec`.
+  pub samples: Vec<::bson::Bson>,
+
+  /// States of the generators of the chains, in the same order as `samples`.
+  #[serde(with = "rng_states")]
+  pub rngs: Vec<Rng>,
+
+  /// Values which haven't been exported before the checkpoint was written.
+  pub measures: Measures,
+
+  /// Parameters of the sample serialized to JSON. Missing in checkpoints
+  /// written by older versions.
+  pub params: Option<String>,
+}
+
+/// Stores the states of the generators as binary blobs. The generators don't
+/// survive the BSON encoding by themselves, so their states are serialized to
+/// JSON, which represents their state words exactly, and the JSON is stored
+/// as BSON binary.
+mod rng_states {
+  use ::rng::Rng;
+  use ::serde::Deserialize;
+  use ::serde::Deserializer;
+  use ::serde::Serializer;
+  use ::serde::de::Error as DeError;
+  use ::serde::ser::Error as SerError;
+  use ::std::fmt;
+
+  pub fn serialize<S: Serializer>(rngs: &[Rng], serializer: S)
+     -> Result<S::Ok, S::Error> {
+    let states: Result<Vec<RngState>, S::Error> = rngs.iter()
+      .map(|rng| ::serde_json::to_vec(rng).map(RngState)
+                   .map_err(S::Error::custom))
+      .collect();
+    serializer.collect_seq(states?)
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D)
+     -> Result<Vec<Rng>, D::Error> {
+    Vec::<RngState>::deserialize(deserializer)?.into_iter()
+      .map(|state| ::serde_json::from_slice(&state.0)
+                     .map_err(D::Error::custom))
+      .collect()
+  }
+
+  /// The serialized state of a single generator.
+  struct RngState(Vec<u8>);
+
+  impl ::serde::Serialize for RngState {
+    fn serialize<S: Serializer>(&self, serializer: S)
+       -> Result<S::Ok, S::Error> {
+      serializer.serialize_bytes(&self.0)
+    }
+  }
+
+  impl<'de> Deserialize<'de> for RngState {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D)
+       -> Result<RngState, D::Error> {
+      deserializer.deserialize_bytes(RngStateVisitor)
+    }
+  }
+
+  struct RngStateVisitor;
+
+  impl<'de> ::serde::de::Visitor<'de> for RngStateVisitor {
+    type Value = RngState;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      f.write_str("the serialized state of a generator")
+    }
+
+    fn visit_bytes<E: DeError>(self, bytes: &[u8]) -> Result<RngState, E> {
+      Ok(RngState(bytes.to_vec()))
+    }
+
+    fn visit_byte_buf<E: DeError>(self, bytes: Vec<u8>)
+       -> Result<RngState, E> {
+      Ok(RngState(bytes))
+    }
+  }
+}
+
+/// Serializes samples of type `S` to BSON and back. Holds the serialization
+/// functions, so that the simulation engine doesn't require samples to be
+/// serializable unless checkpoints are enabled.
+pub struct Codec<S> {
+  encode: fn(&S) -> Result<::bson::Bson, String>,
+  decode: fn(::bson::Bson) -> Result<S, String>,
+}
+
+impl<S: Serialize + DeserializeOwned> Codec<S> {
+  /// Constructs a codec for serializable samples.
+  pub fn new() -> Codec<S> {
+    Codec {
+      encode: |sample| ::bson::to_bson(sample)
+        .map_err(|err| format!("{:?}", err)),
+      decode: |bson| ::bson::from_bson(bson)
+        .map_err(|err| format!("{:?}", err)),
+    }
+  }
+}
+
+impl<S> Codec<S> {
+  /// Serializes the `sample`.
+  pub fn encode(&self, sample: &S) -> Result<::bson::Bson, String> {
+    (self.encode)(sample)
+  }
+
+  /// Deserializes a sample restored from a checkpoint. Panics on failure.
+  pub fn decode(&self, bson: ::bson::Bson) -> S {
+    (self.decode)(bson).unwrap_or_else(
+      |err| panic!("Failed to deserialize a sample from the checkpoint: {}",
+                   err))
+  }
+}
+
+// Implemented by hand, since deriving would require `S: Clone`.
+impl<S> Clone for Codec<S> {
+  fn clone(&self) -> Codec<S> {
+    *self
+  }
+}
+
+impl<S> Copy for Codec<S> {}
+
+/// Periodically writes checkpoints of the simulation state.
+pub struct Checkpointer<S> {
+  settings: CheckpointSettings,
+  codec: Codec<S>,
+
+  /// Parameters of the sample serialized to JSON, which the samples in the
+  /// checkpoint were prepared with. Compared as JSON on resume, since parsing
+  /// doesn't always restore floats exactly.
+  params: String,
+  last_write: Instant,
+
+  /// Set if the last checkpoint contains values which had been waiting for
+  /// the export thread since before the last flush, to the last of those
+  /// flushes. Such a checkpoint is rewritten once they are exported, so that
+  /// resuming from it doesn't export them twice. The values of the last flush
+  /// are not considered, since they are usually still being exported when
+  /// the checkpoint is written, and would cause a rewrite on every flush.
+  stale_flush: Option<usize>,
+}
+
+impl<S> Checkpointer<S> {
+  pub fn new(settings: CheckpointSettings, codec: Codec<S>,
+             params: ::serde_json::Value) -> Checkpointer<S> {
+    Checkpointer {
+      settings,
+      codec,
+      params: params.to_string(),
+      last_write: Instant::now(),
+      stale_flush: None,
+    }
+  }
+
+  /// Gives the codec for serializing samples.
+  pub fn codec(&self) -> Codec<S> {
+    self.codec
+  }
+
+  /// Reads the checkpoint file if resuming was requested. Returns `None` if
+  /// the file doesn't exist. Panics if the file can't be read or parsed, or
+  /// if it was written with different parameters of the sample.
+  pub fn resume(&mut self) -> Option<Checkpoint> {
+    if !self.settings.resume {
+      return None;
+    }
+    let path = &self.settings.path;
+    let bytes = match ::std::fs::read(path) {
+      Ok(bytes) => bytes,
+      Err(ref err) if err.kind() == ::std::io::ErrorKind::NotFound => {
+        info!("Checkpoint {} doesn't exist, starting from scratch.",
+              path.display());
+        return None;
+      },
+      Err(err) => panic!("Failed to read checkpoint {}: {:?}",
+                         path.display(), err),
+    };
+    let checkpoint: Checkpoint = ::bson::decode_document(&mut &bytes[..])
+      .map_err(|err| format!("{:?}", err))
+      .and_then(|doc| ::bson::from_bson(::bson::Bson::Document(doc))
+                        .map_err(|err| format!("{:?}", err)))
+      .unwrap_or_else(|err| panic!("Failed to parse checkpoint {}: {}",
+                                   path.display(), err));
+    if let Some(ref params) = checkpoint.params {
+      if *params != self.params {
+        panic!("Checkpoint {} was written with the parameters of the sample \
+                {}, but the simulation runs with {}. Remove the checkpoint \
+                or drop --resume to start from scratch.",
+               path.display(), params, self.params);
+      }
+    }
+    info!("Resuming from checkpoint {} with {} samples.", path.display(),
+          checkpoint.samples.len());
+    // The restored values are exported with the first flush.
+    if !checkpoint.measures.is_empty() {
+      self.stale_flush = Some(1);
+    }
+    Some(checkpoint)
+  }
+
+  /// Returns true if a checkpoint should be written on this flush, given the
+  /// last flush whose values have all been exported.
+  pub fn is_due(&self, delivered: usize) -> bool {
+    self.stale_flush.map_or(false, |flush| delivered >= flush) ||
+      self.last_write.elapsed() >= self.settings.interval
+  }
+
+  /// Writes serialized samples of the `chains` along with their generators
+  /// and the values which haven't been exported yet to the checkpoint file.
+  /// `progress` gives the number of flushes so far and the last flush whose
+  /// values have all been exported, as reported by the export thread. Errors
+  /// are logged, and the simulation continues.
+  pub fn write(&mut self, chains: Vec<(Result<::bson::Bson, String>, Rng)>,
+               measures: &Measures, progress: (usize, usize)) {
+    self.last_write = Instant::now();
+    let (samples, rngs): (Vec<_>, Vec<_>) = chains.into_iter().unzip();
+    let samples: Result<Vec<_>, _> = samples.into_iter().collect();
+    let checkpoint = match samples {
+      Ok(samples) => Checkpoint {
+        samples,
+        rngs,
+        measures: measures.clone(),
+        params: Some(self.params.clone()),
+      },
+      Err(err) => {
+        error!("Failed to serialize a sample: {}", err);
+        return;
+      },
+    };
+    let path = &self.settings.path;
+    let mut bytes = Vec::new();
+    let result = match ::bson::to_bson(&checkpoint) {
+      Ok(::bson::Bson::Document(doc)) =>
+        ::bson::encode_document(&mut bytes, &doc)
+          .map_err(|err| format!("{:?}", err)),
+      Ok(other) => Err(format!("Expected a document, found {}", other)),
+      Err(err) => Err(format!("{:?}", err)),
+    };
+    if let Err(err) = result {
+      error!("Failed to serialize a checkpoint: {}", err);
+      return;
+    }
+    // Write to a temporary file first, so that the checkpoint is never left
+    // incomplete if the process is killed in the middle of writing.
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let result = ::std::fs::write(&tmp_path, &bytes)
+      .and_then(|()| ::std::fs::rename(&tmp_path, path));
+    match result {
+      Ok(()) => {
+        let (flushes, delivered) = progress;
+        self.stale_flush = if !checkpoint.measures.is_empty() &&
+                              delivered + 1 < flushes {
+          Some(flushes - 1)
+        } else {
+          None
+        };
+        info!("Checkpoint with {} samples written to {}.",
+              checkpoint.samples.len(), path.display());
+      },
+      Err(err) => {
+        error!("Failed to write checkpoint {}: {:?}", path.display(), err);
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::CheckpointSettings;
+  use super::Checkpointer;
+  use super::Codec;
+  use ::measure::Measures;
+  use ::rand::RngCore;
+  use ::rng::chain_rng;
+  use ::std::time::Duration;
+
+  fn params(beta: f64) -> ::serde_json::Value {
+    ::serde_json::from_str(&format!("{{\"beta\": {}, \"size\": 8}}", beta))
+      .unwrap()
+  }
+
+  #[test]
+  fn checkpoint_round_trip() {
+    let path = ::std::env::temp_dir().join(
+      format!("ergothic-checkpoint-test-{}", ::std::process::id()));
+    let settings = CheckpointSettings {
+      path: path.clone(),
+      interval: Duration::from_secs(0),
+      resume: true,
+    };
+    let mut checkpointer =
+      Checkpointer::new(settings, Codec::<Vec<f64>>::new(), params(5.7));
+    let mut measures = Measures::new_empty();
+    let idx = measures.find_or_register("x");
+    measures.accumulate(idx, 2.0);
+    let sample = vec![1.0, 2.0];
+    let mut rng = chain_rng(42, 0, 0);
+    rng.next_u64();
+    let chains = vec![(checkpointer.codec().encode(&sample), rng.clone())];
+    checkpointer.write(chains, &measures, (1, 0));
+
+    let checkpoint = checkpointer.resume().expect("No checkpoint written.");
+    ::std::fs::remove_file(&path).unwrap();
+    assert_eq!(checkpoint.samples.len(), 1);
+    let restored: Vec<f64> =
+      checkpointer.codec().decode(checkpoint.samples[0].clone());
+    assert_eq!(restored, sample);
+    let mut restored_rng = checkpoint.rngs[0].clone();
+    for _ in 0..1000 {
+      assert_eq!(restored_rng.next_u64(), rng.next_u64());
+    }
+    let idx = checkpoint.measures.find("x").expect("Measure x is missing.");
+    assert_eq!(checkpoint.measures.get(idx).acc.num_of_samples(), 1.0);
+  }
+
+  #[test]
+  fn checkpoint_isnt_rewritten_on_every_flush() {
+    let path = ::std::env::temp_dir().join(
+      format!("ergothic-checkpoint-due-test-{}", ::std::process::id()));
+    let settings = CheckpointSettings {
+      path: path.clone(),
+      interval: Duration::from_secs(3600),
+      resume: false,
+    };
+    let mut checkpointer =
+      Checkpointer::new(settings, Codec::<Vec<f64>>::new(), params(5.7));
+    let mut measures = Measures::new_empty();
+    let idx = measures.find_or_register("x");
+    measures.accumulate(idx, 2.0);
+    let codec = checkpointer.codec();
+    let chains = || vec![(codec.encode(&vec![1.0]), chain_rng(42, 0, 0))];
+
+    // Only the values of the last flush are waiting to be exported.
+    checkpointer.write(chains(), &measures, (1, 0));
+    assert!(!checkpointer.is_due(0));
+    assert!(!checkpointer.is_due(1));
+    checkpointer.write(chains(), &measures, (2, 1));
+    assert!(!checkpointer.is_due(2));
+
+    // The values of flushes 3 and 4 are still waiting, so the checkpoint is
+    // rewritten once those of flush 3 are exported.
+    checkpointer.write(chains(), &measures, (4, 2));
+    assert!(!checkpointer.is_due(2));
+    assert!(checkpointer.is_due(3));
+    ::std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  #[should_panic(expected = "was written with the parameters of the sample")]
+  fn resume_refuses_different_params() {
+    let path = ::std::env::temp_dir().join(
+      format!("ergothic-checkpoint-params-test-{}", ::std::process::id()));
+    let settings = CheckpointSettings {
+      path: path.clone(),
+      interval: Duration::from_secs(0),
+      resume: true,
+    };
+    let mut checkpointer = Checkpointer::new(
+      settings.clone(), Codec::<Vec<f64>>::new(), params(5.7));
+    let chains = vec![(checkpointer.codec().encode(&vec![1.0]),
+                       chain_rng(42, 0, 0))];
+    checkpointer.write(chains, &Measures::new_empty(), (1, 0));
+    let mut resumed =
+      Checkpointer::new(settings, Codec::<Vec<f64>>::new(), params(6.0));
+    let result = ::std::panic::catch_unwind(
+      ::std::panic::AssertUnwindSafe(|| resumed.resume()));
+    ::std::fs::remove_file(&path).unwrap();
+    if let Err(err) = result {
+      ::std::panic::resume_unwind(err);
+    }
+  }
+}
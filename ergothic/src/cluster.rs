@@ -0,0 +1,576 @@
+//! The spins live on the sites of a periodic hypercubic `Lattice`, and
+//! interact with their nearest neighbours according to the `Model`: `Ising`,
+//! `Potts` or `ONModel`. The `Wolff` and `SwendsenWang` helpers update a
+//! `SpinLattice` by flipping whole clusters of spins, which greatly reduces
+//! the critical slowing down compared to local updates. Both keep track of
+//! the mean size of the flipped clusters, recorded once the sample reports it
+//! in `Sample::report_statistics`:
+//!
+//! ```no_run
+//! # extern crate ergothic;
+//! use ergothic::cluster::{Ising, Lattice, SpinLattice, Wolff};
+//!
+//! struct MySample {
+//!   spins: SpinLattice<Ising>,
+//!   wolff: Wolff,
+//! }
+//!
+//! impl ergothic::SeededSample for MySample {
+//!   fn prepare(rng: &mut ergothic::Rng) -> MySample {
+//!     MySample {
+//!       // 32x32 lattice at the critical coupling.
+//!       spins: SpinLattice::new(Ising, Lattice::new(&[32, 32]), 0.4407, rng),
+//!       wolff: Wolff::new(),
+//!     }
+//!   }
+//!
+//!   fn mutate(&mut self, rng: &mut ergothic::Rng) {
+//!     self.wolff.update(&mut self.spins, rng);
+//!   }
+//!
+//!   fn action(&self) -> Option<f64> {
+//!     Some(self.spins.beta * self.spins.energy())
+//!   }
+//!
+//!   fn report_statistics(&mut self, stats: &mut ergothic::UpdateStats) {
+//!     self.wolff.report("cluster_size", stats);
+//!   }
+//! }
+//! # fn main() {}
+//! ```
+//!
+//! All models are updated with the embedding of Wolff: a random reflection
+//! of the spin space is chosen, and clusters of spins are reflected as a
+//! whole. For the Potts model, the reflection swaps two of the states.
+
+use ::rand::Rng as RandRng;
+use ::rand::distributions::StandardNormal;
+use ::rng::Rng;
+use ::serde::Serialize;
+use ::serde::de::DeserializeOwned;
+use ::statistics::UpdateStats;
+use ::std::fmt::Debug;
+
+/// A periodic hypercubic lattice. Sites are indexed from 0 to
+/// `num_sites() - 1`, with the first coordinate changing the fastest.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Lattice {
+  dims: Vec<usize>,
+
+  /// The `2 * dims.len()` neighbours of every site, forward ones first.
+  neighbours: Vec<usize>,
+}
+
+impl Lattice {
+  /// Constructs a lattice of the given extents. Panics if any of them is
+  /// shorter than 3, since the forward and the backward neighbours would
+  /// coincide.
+  pub fn new(dims: &[usize]) -> Lattice {
+    assert!(!dims.is_empty(),
+            "The lattice should have at least one dimension.");
+    for &dim in dims.iter() {
+      assert!(dim >= 3, "The lattice should be at least 3 sites long in \
+                         every dimension, found {}.", dim);
+    }
+    let num_sites: usize = dims.iter().product();
+    let mut neighbours = Vec::with_capacity(2 * dims.len() * num_sites);
+    for site in 0..num_sites {
+      let mut stride = 1;
+      let mut forward = Vec::with_capacity(dims.len());
+      let mut backward = Vec::with_capacity(dims.len());
+      for &dim in dims.iter() {
+        let coordinate = (site / stride) % dim;
+        let base = site - coordinate * stride;
+        forward.push(base + (coordinate + 1) % dim * stride);
+        backward.push(base + (coordinate + dim - 1) % dim * stride);
+        stride *= dim;
+      }
+      neighbours.extend(forward);
+      neighbours.extend(backward);
+    }
+    Lattice {
+      dims: dims.to_vec(),
+      neighbours,
+    }
+  }
+
+  /// Gives the extents of the lattice.
+  pub fn dims(&self) -> &[usize] {
+    &self.dims
+  }
+
+  /// Gives the number of sites.
+  pub fn num_sites(&self) -> usize {
+    self.neighbours.len() / (2 * self.dims.len())
+  }
+
+  /// Gives the nearest neighbours of the `site`: the forward neighbour in
+  /// each of the dimensions, followed by the backward ones.
+  pub fn neighbours(&self, site: usize) -> &[usize] {
+    let n = 2 * self.dims.len();
+    &self.neighbours[site * n..(site + 1) * n]
+  }
+
+  /// Gives the forward neighbours of the `site`. Every bond of the lattice
+  /// connects a site with one of its forward neighbours.
+  pub fn forward_neighbours(&self, site: usize) -> &[usize] {
+    let n = self.dims.len();
+    &self.neighbours(site)[..n]
+  }
+}
+
+/// A spin model with nearest-neighbour interactions, updated by reflecting
+/// clusters of spins.
+pub trait Model {
+  /// Value of a single spin. Serializable, so that the configurations can be
+  /// checkpointed.
+  type Spin: Clone + Debug + Serialize + DeserializeOwned;
+
+  /// A reflection of the spin space, which preserves the energy of the bonds.
+  type Reflection;
+
+  /// Draws a spin from the uniform distribution.
+  fn random_spin(&self, rng: &mut Rng) -> Self::Spin;
+
+  /// Draws a reflection given the spin at the `seed` site. The reflection
+  /// must be drawn with the same probability given the reflected seed spin,
+  /// otherwise the updates violate detailed balance.
+  fn random_reflection(&self, seed: &Self::Spin, rng: &mut Rng)
+     -> Self::Reflection;
+
+  /// Reflects the `spin`. Applying the same reflection twice must give the
+  /// original spin.
+  fn reflect(&self, spin: &Self::Spin, reflection: &Self::Reflection)
+     -> Self::Spin;
+
+  /// Gives the energy of the bond between neighbouring spins `a` and `b`.
+  fn energy(&self, a: &Self::Spin, b: &Self::Spin) -> f64;
+}
+
+/// The Ising model, whose spins are either +1 or -1. The energy of a bond is
+/// `-a * b`.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Ising;
+
+impl Model for Ising {
+  type Spin = i8;
+  type Reflection = ();
+
+  fn random_spin(&self, rng: &mut Rng) -> i8 {
+    if rng.gen::<bool>() { 1 } else { -1 }
+  }
+
+  fn random_reflection(&self, _seed: &i8, _rng: &mut Rng) {}
+
+  fn reflect(&self, spin: &i8, _reflection: &()) -> i8 {
+    -spin
+  }
+
+  fn energy(&self, a: &i8, b: &i8) -> f64 {
+    -f64::from(a * b)
+  }
+}
+
+/// The `q`-state Potts model, whose spins take values from 0 to `q - 1`. The
+/// energy of a bond is -1 if the spins are equal, and 0 otherwise.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Potts {
+  /// Number of the states of a spin.
+  pub q: usize,
+}
+
+impl Model for Potts {
+  type Spin = usize;
+
+  /// The two states swapped by the reflection.
+  type Reflection = (usize, usize);
+
+  fn random_spin(&self, rng: &mut Rng) -> usize {
+    rng.gen_range(0, self.q)
+  }
+
+  fn random_reflection(&self, seed: &usize, rng: &mut Rng) -> (usize, usize) {
+    assert!(self.q >= 2, "The Potts model should have at least 2 states.");
+    let other = (seed + rng.gen_range(1, self.q)) % self.q;
+    (*seed, other)
+  }
+
+  fn reflect(&self, spin: &usize, reflection: &(usize, usize)) -> usize {
+    let (a, b) = *reflection;
+    if *spin == a {
+      b
+    } else if *spin == b {
+      a
+    } else {
+      *spin
+    }
+  }
+
+  fn energy(&self, a: &usize, b: &usize) -> f64 {
+    if a == b { -1.0 } else { 0.0 }
+  }
+}
+
+/// The O(N) model, whose spins are unit vectors with `n` components. The
+/// energy of a bond is minus the scalar product of the spins. For `n` equal
+/// to 2 and 3, this is the XY and the Heisenberg model respectively.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ONModel {
+  /// Number of the components of a spin.
+  pub n: usize,
+}
+
+impl ONModel {
+  /// Draws a vector uniformly distributed on the unit sphere.
+  fn random_unit_vector(&self, rng: &mut Rng) -> Vec<f64> {
+    assert!(self.n >= 1, "The spins should have at least one component.");
+    loop {
+      let v: Vec<f64> =
+        (0..self.n).map(|_| rng.sample(StandardNormal)).collect();
+      let norm = dot(&v, &v).sqrt();
+      if norm > 0.0 {
+        return v.into_iter().map(|x| x / norm).collect();
+      }
+    }
+  }
+}
+
+/// Scalar product of two vectors.
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+  a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+impl Model for ONModel {
+  type Spin = Vec<f64>;
+
+  /// Unit normal of the reflection hyperplane.
+  type Reflection = Vec<f64>;
+
+  fn random_spin(&self, rng: &mut Rng) -> Vec<f64> {
+    self.random_unit_vector(rng)
+  }
+
+  fn random_reflection(&self, _seed: &Vec<f64>, rng: &mut Rng) -> Vec<f64> {
+    self.random_unit_vector(rng)
+  }
+
+  fn reflect(&self, spin: &Vec<f64>, reflection: &Vec<f64>) -> Vec<f64> {
+    let projection = dot(spin, reflection);
+    spin.iter().zip(reflection.iter())
+      .map(|(s, r)| s - 2.0 * projection * r)
+      .collect()
+  }
+
+  fn energy(&self, a: &Vec<f64>, b: &Vec<f64>) -> f64 {
+    -dot(a, b)
+  }
+}
+
+/// Spins of the model `M` on the sites of a lattice, at the inverse
+/// temperature `beta`. The statistical weight of a configuration is
+/// `exp(-beta * energy())`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpinLattice<M: Model> {
+  /// The spin model.
+  pub model: M,
+
+  /// The lattice the spins live on.
+  pub lattice: Lattice,
+
+  /// The inverse temperature.
+  pub beta: f64,
+
+  /// The spin on each site of the lattice.
+  pub spins: Vec<M::Spin>,
+}
+
+impl<M: Model> SpinLattice<M> {
+  /// Constructs a configuration with randomly drawn spins, which corresponds
+  /// to the infinite temperature.
+  pub fn new(model: M, lattice: Lattice, beta: f64, rng: &mut Rng)
+         -> SpinLattice<M> {
+    let spins = (0..lattice.num_sites())
+      .map(|_| model.random_spin(rng))
+      .collect();
+    SpinLattice {
+      model,
+      lattice,
+      beta,
+      spins,
+    }
+  }
+
+  /// Gives the total energy of the bonds.
+  pub fn energy(&self) -> f64 {
+    let mut energy = 0.0;
+    for site in 0..self.spins.len() {
+      for &neighbour in self.lattice.forward_neighbours(site) {
+        energy += self.model.energy(&self.spins[site], &self.spins[neighbour]);
+      }
+    }
+    energy
+  }
+
+  /// Gives the probability of binding the spins `a` and `b` into the same
+  /// cluster under the `reflection`.
+  fn bond_probability(&self, a: &M::Spin, b: &M::Spin,
+                      reflection: &M::Reflection) -> f64 {
+    let reflected = self.model.reflect(a, reflection);
+    let delta = self.model.energy(&reflected, b) - self.model.energy(a, b);
+    if delta > 0.0 {
+      1.0 - (-self.beta * delta).exp()
+    } else {
+      0.0
+    }
+  }
+}
+
+/// Accumulates the sizes of the flipped clusters.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+struct ClusterSizes {
+  total: f64,
+  clusters: f64,
+}
+
+impl ClusterSizes {
+  /// Records the mean cluster size in the diagnostic measure
+  /// `__ergothic/<name>`, and resets the sizes.
+  fn report(&mut self, name: &str, stats: &mut UpdateStats) {
+    if self.clusters > 0.0 {
+      stats.record(name, self.total / self.clusters);
+    }
+    *self = ClusterSizes::default();
+  }
+}
+
+/// Single-cluster updates by Wolff. Every update grows a cluster from a
+/// randomly chosen site and reflects it.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Wolff {
+  sizes: ClusterSizes,
+}
+
+impl Wolff {
+  /// Constructs the helper.
+  pub fn new() -> Wolff {
+    Wolff::default()
+  }
+
+  /// Grows and reflects a single cluster. Returns its size.
+  pub fn update<M: Model>(&mut self, lattice: &mut SpinLattice<M>,
+                          rng: &mut Rng) -> usize {
+    let num_sites = lattice.spins.len();
+    let seed = rng.gen_range(0, num_sites);
+    let reflection =
+      lattice.model.random_reflection(&lattice.spins[seed], rng);
+    let mut in_cluster = vec![false; num_sites];
+    // Sites added to the cluster, with their spins before the reflection.
+    let mut frontier = Vec::new();
+    in_cluster[seed] = true;
+    frontier.push((seed, lattice.spins[seed].clone()));
+    lattice.spins[seed] =
+      lattice.model.reflect(&lattice.spins[seed], &reflection);
+    let mut size = 1;
+    while let Some((site, spin)) = frontier.pop() {
+      for &neighbour in lattice.lattice.neighbours(site) {
+        if in_cluster[neighbour] {
+          continue;
+        }
+        let p = lattice.bond_probability(&spin, &lattice.spins[neighbour],
+                                         &reflection);
+        if rng.gen::<f64>() < p {
+          in_cluster[neighbour] = true;
+          let old_spin = lattice.spins[neighbour].clone();
+          lattice.spins[neighbour] =
+            lattice.model.reflect(&old_spin, &reflection);
+          frontier.push((neighbour, old_spin));
+          size += 1;
+        }
+      }
+    }
+    self.sizes.total += size as f64;
+    self.sizes.clusters += 1.0;
+    size
+  }
+
+  /// Records the mean size of the clusters flipped since the last report in
+  /// the diagnostic measure `__ergothic/<name>`.
+  pub fn report(&mut self, name: &str, stats: &mut UpdateStats) {
+    self.sizes.report(name, stats);
+  }
+}
+
+/// Multi-cluster updates by Swendsen and Wang. Every update decomposes the
+/// whole lattice into clusters, and reflects each of them with the
+/// probability 1/2.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SwendsenWang {
+  sizes: ClusterSizes,
+}
+
+/// Finds the root of the cluster containing the `site`, compressing the path
+/// to it.
+fn find_root(parents: &mut [usize], mut site: usize) -> usize {
+  while parents[site] != site {
+    parents[site] = parents[parents[site]];
+    site = parents[site];
+  }
+  site
+}
+
+impl SwendsenWang {
+  /// Constructs the helper.
+  pub fn new() -> SwendsenWang {
+    SwendsenWang::default()
+  }
+
+  /// Decomposes the lattice into clusters and reflects them. Returns the
+  /// number of clusters.
+  pub fn update<M: Model>(&mut self, lattice: &mut SpinLattice<M>,
+                          rng: &mut Rng) -> usize {
+    let num_sites = lattice.spins.len();
+    let seed = rng.gen_range(0, num_sites);
+    let reflection =
+      lattice.model.random_reflection(&lattice.spins[seed], rng);
+    let mut parents: Vec<usize> = (0..num_sites).collect();
+    for site in 0..num_sites {
+      for &neighbour in lattice.lattice.forward_neighbours(site) {
+        let p = lattice.bond_probability(&lattice.spins[site],
+                                         &lattice.spins[neighbour],
+                                         &reflection);
+        if rng.gen::<f64>() < p {
+          let a = find_root(&mut parents, site);
+          let b = find_root(&mut parents, neighbour);
+          parents[a] = b;
+        }
+      }
+    }
+    let mut flip = vec![None; num_sites];
+    let mut clusters = 0;
+    for site in 0..num_sites {
+      let root = find_root(&mut parents, site);
+      let flipped = *flip[root].get_or_insert_with(|| {
+        clusters += 1;
+        rng.gen::<bool>()
+      });
+      if flipped {
+        lattice.spins[site] =
+          lattice.model.reflect(&lattice.spins[site], &reflection);
+      }
+    }
+    self.sizes.total += num_sites as f64;
+    self.sizes.clusters += clusters as f64;
+    clusters
+  }
+
+  /// Records the mean size of the clusters since the last report in the
+  /// diagnostic measure `__ergothic/<name>`.
+  pub fn report(&mut self, name: &str, stats: &mut UpdateStats) {
+    self.sizes.report(name, stats);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Ising;
+  use super::Lattice;
+  use super::SpinLattice;
+  use super::SwendsenWang;
+  use super::Wolff;
+  use ::rng::Rng;
+  use ::rng::chain_rng;
+
+  /// Inverse temperature of the tests on the 3×3 lattice.
+  const BETA: f64 = 0.4;
+
+  fn ising(beta: f64, rng: &mut Rng) -> SpinLattice<Ising> {
+    SpinLattice::new(Ising, Lattice::new(&[3, 3]), beta, rng)
+  }
+
+  /// Mean energy of the Ising model on the 3×3 lattice, computed by
+  /// enumerating all of its configurations.
+  fn exact_energy(beta: f64) -> f64 {
+    let mut lattice = ising(beta, &mut chain_rng(1, 0, 0));
+    let (mut weights, mut energies) = (0.0, 0.0);
+    for configuration in 0..1 << 9 {
+      for (site, spin) in lattice.spins.iter_mut().enumerate() {
+        *spin = if configuration & (1 << site) != 0 { 1 } else { -1 };
+      }
+      let energy = lattice.energy();
+      let weight = (-beta * energy).exp();
+      weights += weight;
+      energies += weight * energy;
+    }
+    energies / weights
+  }
+
+  /// Mean energy over the configurations produced by `update`.
+  fn mean_energy<F>(mut update: F) -> f64
+    where F: FnMut(&mut SpinLattice<Ising>, &mut Rng) {
+    let mut rng = chain_rng(1, 0, 0);
+    let mut lattice = ising(BETA, &mut rng);
+    for _ in 0..1000 {
+      update(&mut lattice, &mut rng);
+    }
+    let samples = 100000;
+    let mut energy = 0.0;
+    for _ in 0..samples {
+      update(&mut lattice, &mut rng);
+      energy += lattice.energy();
+    }
+    energy / samples as f64
+  }
+
+  #[test]
+  fn lattice_neighbours() {
+    let lattice = Lattice::new(&[3, 4]);
+    assert_eq!(lattice.num_sites(), 12);
+    assert_eq!(lattice.neighbours(0), &[1, 3, 2, 9]);
+    assert_eq!(lattice.neighbours(5), &[3, 8, 4, 2]);
+    assert_eq!(lattice.forward_neighbours(11), &[9, 2]);
+  }
+
+  #[test]
+  #[should_panic(expected = "at least 3 sites long")]
+  fn short_lattice() {
+    Lattice::new(&[3, 2]);
+  }
+
+  #[test]
+  fn wolff_matches_exact_energy() {
+    let mut wolff = Wolff::new();
+    let energy = mean_energy(|lattice, rng| { wolff.update(lattice, rng); });
+    let exact = exact_energy(BETA);
+    assert!((energy - exact).abs() < 0.05, "{} vs {}.", energy, exact);
+  }
+
+  #[test]
+  fn swendsen_wang_matches_exact_energy() {
+    let mut sw = SwendsenWang::new();
+    let energy = mean_energy(|lattice, rng| { sw.update(lattice, rng); });
+    let exact = exact_energy(BETA);
+    assert!((energy - exact).abs() < 0.05, "{} vs {}.", energy, exact);
+  }
+
+  #[test]
+  fn clusters_are_single_sites_at_infinite_temperature() {
+    let mut rng = chain_rng(1, 0, 0);
+    let mut lattice = ising(0.0, &mut rng);
+    assert_eq!(Wolff::new().update(&mut lattice, &mut rng), 1);
+    assert_eq!(SwendsenWang::new().update(&mut lattice, &mut rng), 9);
+  }
+
+  #[test]
+  fn ordered_lattice_is_a_single_cluster_at_low_temperature() {
+    let mut rng = chain_rng(1, 0, 0);
+    let mut lattice = ising(100.0, &mut rng);
+    for spin in lattice.spins.iter_mut() {
+      *spin = 1;
+    }
+    assert_eq!(lattice.energy(), -18.0);
+    assert_eq!(Wolff::new().update(&mut lattice, &mut rng), 9);
+    assert!(lattice.spins.iter().all(|&spin| spin == -1));
+    assert_eq!(SwendsenWang::new().update(&mut lattice, &mut rng), 1);
+    assert_eq!(lattice.energy(), -18.0);
+  }
+}
@@ -0,0 +1,275 @@
+use ::hooks::ThermalizeHook;
+use ::rng::Rng;
+use ::simulation::ParameterizedSample;
+use ::statistics::UpdateStats;
+
+/// A function of the sample tracked during the adaptive thermalization. Shared,
+/// so that the simulation can be run more than once, as in parameter sweeps.
+pub type Tracer<S> = ::std::sync::Arc<dyn Fn(&S) -> f64 + Send + Sync>;
+
+/// Settings of the adaptive thermalization phase, which replaces
+/// `Sample::thermalize`.
+#[derive(Clone, Debug)]
+pub struct EquilibrationSettings {
+  /// The number of mutations after which stationarity is checked for the
+  /// first time. Subsequent checks happen each time the number of mutations
+  /// doubles.
+  pub min_mutations: usize,
+
+  /// Give up on detecting stationarity after this many mutations, and
+  /// continue the simulation as if the sample was thermalized.
+  pub max_mutations: usize,
+}
+
+/// Size of the batches of subsequent values averaged before computing the
+/// truncation point, as in the MSER-5 rule.
+const MSER_BATCH: usize = 5;
+
+/// Computes the MSER truncation point of the `series`: the number of leading
+/// values which should be discarded to minimize the squared standard error of
+/// the mean of the remaining values. Returns `None` unless the optimal
+/// truncation point lies in the first half of the series: otherwise, the
+/// series is too short to be stationary.
+fn mser_truncation(series: &[f64]) -> Option<usize> {
+  let batches: Vec<f64> = series.chunks(MSER_BATCH)
+    .filter(|chunk| chunk.len() == MSER_BATCH)
+    .map(|chunk| chunk.iter().sum::<f64>() / MSER_BATCH as f64)
+    .collect();
+  let n = batches.len();
+  if n < 4 {
+    return None;
+  }
+  let mut best = (0, f64::INFINITY);
+  for d in 0..n - 1 {
+    let tail = &batches[d..];
+    let len = tail.len() as f64;
+    let mean = tail.iter().sum::<f64>() / len;
+    let ss = tail.iter().map(|y| (y - mean).powi(2)).sum::<f64>();
+    let statistic = ss / (len * len);
+    if statistic < best.1 {
+      best = (d, statistic);
+    }
+  }
+  if 2 * best.0 < n {
+    Some(best.0 * MSER_BATCH)
+  } else {
+    None
+  }
+}
+
+/// Mutates a freshly prepared `sample` until the tracked values become
+/// stationary according to the MSER rule. The tracked values are the
+/// `tracers`, or the action of the sample if there are no tracers. Returns the
+/// number of mutations performed, or `None` if there is nothing to track, in
+/// which case the sample is thermalized with `ParameterizedSample::thermalize`.
+/// Stops early if the process is interrupted by a signal.
+pub fn equilibrate<S: ParameterizedSample>(sample: &mut S, rng: &mut Rng,
+                                    tracers: &[Tracer<S>],
+                                    settings: &EquilibrationSettings)
+       -> Option<usize> {
+  let trace = |sample: &S| -> Vec<f64> {
+    if tracers.is_empty() {
+      sample.action().into_iter().collect()
+    } else {
+      tracers.iter().map(|tracer| tracer(sample)).collect()
+    }
+  };
+  let mut series: Vec<Vec<f64>> = trace(sample).into_iter()
+    .map(|value| vec![value])
+    .collect();
+  if series.is_empty() {
+    warn!("Nothing to track for the adaptive thermalization. Register \
+           equilibration tracers or implement Sample::action.");
+    sample.thermalize(rng);
+    return None;
+  }
+
+  let mut mutations = 0;
+  let mut next_check = ::std::cmp::max(settings.min_mutations, 4 * MSER_BATCH);
+  loop {
+    if ::stop::interrupted() {
+      warn!("Thermalization interrupted after {} mutations.", mutations);
+      return Some(mutations);
+    }
+    sample.mutate(rng);
+    mutations += 1;
+    for (values, value) in series.iter_mut().zip(trace(sample)) {
+      values.push(value);
+    }
+    if mutations >= settings.max_mutations {
+      warn!("Tracked values haven't become stationary after {} mutations.",
+            mutations);
+      return Some(mutations);
+    }
+    if mutations == next_check {
+      let truncation: Option<Vec<usize>> = series.iter()
+        .map(|values| mser_truncation(values))
+        .collect();
+      if let Some(truncation) = truncation {
+        info!("Thermalized after {} mutations. The MSER truncation point is \
+               at {} mutations.", mutations,
+              truncation.iter().max().unwrap());
+        return Some(mutations);
+      }
+      next_check *= 2;
+    }
+  }
+}
+
+/// Prepares new samples with the given parameters, and thermalizes them either
+/// with `ParameterizedSample::thermalize` or adaptively.
+pub struct Thermalizer<S: ParameterizedSample> {
+  /// Parameters new samples are prepared with.
+  pub params: S::Params,
+
+  /// Settings of the adaptive thermalization. If `None`,
+  /// `ParameterizedSample::thermalize` is used.
+  pub settings: Option<EquilibrationSettings>,
+
+  /// Functions of the sample tracked during the adaptive thermalization.
+  pub tracers: Vec<Tracer<S>>,
+
+  /// Hooks called on every sample once it has been thermalized.
+  pub after_thermalize: Vec<ThermalizeHook<S>>,
+}
+
+impl<S: ParameterizedSample> Thermalizer<S> {
+  /// Thermalizes the `sample` and discards the statistics of its update
+  /// algorithm. Returns the number of mutations performed by the adaptive
+  /// thermalization, or `None` if it didn't run. The adaptive thermalization
+  /// stops early if the process is interrupted by a signal, so a sample
+  /// thermalized after `::stop::interrupted()` became true has to be
+  /// discarded.
+  pub fn thermalize(&self, sample: &mut S, rng: &mut Rng) -> Option<usize> {
+    let mutations = match self.settings {
+      Some(ref settings) => equilibrate(sample, rng, &self.tracers, settings),
+      None => {
+        sample.thermalize(rng);
+        None
+      },
+    };
+    if ::stop::interrupted() {
+      return mutations;
+    }
+    for hook in self.after_thermalize.iter() {
+      hook(sample);
+    }
+    // The statistics of the thermalization are not representative.
+    sample.report_statistics(&mut UpdateStats::after_thermalization());
+    mutations
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::EquilibrationSettings;
+  use super::equilibrate;
+  use super::mser_truncation;
+  use ::rand::Rng as RandRng;
+  use ::rng::Rng;
+  use ::rng::chain_rng;
+  use ::simulation::ParameterizedSample;
+
+  /// Uniform noise in [-1, 1).
+  fn noise(rng: &mut Rng, len: usize) -> Vec<f64> {
+    (0..len).map(|_| rng.gen_range(-1.0, 1.0)).collect()
+  }
+
+  #[test]
+  fn short_series_is_not_stationary() {
+    let mut rng = chain_rng(1, 0, 0);
+    assert_eq!(mser_truncation(&noise(&mut rng, 19)), None);
+  }
+
+  #[test]
+  fn stationary_series_is_not_truncated_much() {
+    let mut rng = chain_rng(1, 0, 0);
+    let truncation = mser_truncation(&noise(&mut rng, 1000))
+      .expect("Stationary noise should be detected as stationary.");
+    assert!(truncation < 250, "Truncated at {}.", truncation);
+  }
+
+  #[test]
+  fn transient_is_truncated() {
+    let mut rng = chain_rng(1, 0, 0);
+    let mut series: Vec<f64> = (0..100).map(|i| 50.0 - 0.5 * i as f64)
+      .collect();
+    series.extend(noise(&mut rng, 400));
+    let truncation = mser_truncation(&series)
+      .expect("The series is stationary after the transient.");
+    assert!((80..=120).contains(&truncation),
+            "Truncated at {}.", truncation);
+  }
+
+  #[test]
+  fn drifting_series_is_not_stationary() {
+    let series: Vec<f64> = (0..500).map(|i| i as f64).collect();
+    assert_eq!(mser_truncation(&series), None);
+  }
+
+  /// Relaxes exponentially from a large initial value towards noise around
+  /// zero. Reports its value as the action if prepared with `true`.
+  struct Relaxing {
+    drift: f64,
+    value: f64,
+    has_action: bool,
+    thermalized: bool,
+  }
+
+  impl ParameterizedSample for Relaxing {
+    type Params = bool;
+
+    fn prepare(has_action: &bool, _rng: &mut Rng) -> Relaxing {
+      Relaxing {
+        drift: 100.0,
+        value: 100.0,
+        has_action: *has_action,
+        thermalized: false,
+      }
+    }
+
+    fn thermalize(&mut self, _rng: &mut Rng) {
+      self.thermalized = true;
+    }
+
+    fn mutate(&mut self, rng: &mut Rng) {
+      self.drift *= 0.9;
+      self.value = self.drift + rng.gen_range(-1.0, 1.0);
+    }
+
+    fn action(&self) -> Option<f64> {
+      if self.has_action {
+        Some(self.value)
+      } else {
+        None
+      }
+    }
+  }
+
+  fn settings() -> EquilibrationSettings {
+    EquilibrationSettings {
+      min_mutations: 20,
+      max_mutations: 100000,
+    }
+  }
+
+  #[test]
+  fn equilibrate_stops_after_the_transient() {
+    let mut rng = chain_rng(1, 0, 0);
+    let mut sample = Relaxing::prepare(&true, &mut rng);
+    let mutations = equilibrate(&mut sample, &mut rng, &[], &settings())
+      .expect("The action should be tracked.");
+    // The drift falls below the noise after about 45 mutations, and checks
+    // happen after 20, 40, 80, ... mutations.
+    assert!((80..=320).contains(&mutations), "Took {}.", mutations);
+    assert!(!sample.thermalized);
+  }
+
+  #[test]
+  fn equilibrate_falls_back_without_tracked_values() {
+    let mut rng = chain_rng(1, 0, 0);
+    let mut sample = Relaxing::prepare(&false, &mut rng);
+    assert_eq!(equilibrate(&mut sample, &mut rng, &[], &settings()), None);
+    assert!(sample.thermalized);
+  }
+}
@@ -1,5 +1,5 @@
 use ::measure::Measures;
-use ::measure::MeasureRegistry;
+use ::mongodb::coll::results::InsertOneResult;
 use ::std::time::SystemTime;
 
 /// Errors returned by the exporter. Contain a string describing the cause of
@@ -7,32 +7,78 @@ use ::std::time::SystemTime;
 #[derive(Debug)]
 pub struct ExportError(pub String);
 
+/// Node indices are limited to 63 bits, so that they can be stored in BSON as
+/// signed integers.
+pub const MAX_NODE_INDEX: u64 = (1 << 63) - 1;
+
+/// Information about the simulation run, recorded in every exported data point
+/// alongside the measures.
+#[derive(Clone, Debug, Serialize)]
+pub struct Metadata {
+  /// Seed of the random number generators of the chains.
+  pub seed: u64,
+
+  /// Index of the node, distinguishing the streams of random numbers of
+  /// different nodes running with the same seed.
+  pub node_index: u64,
+
+  /// Number of mutations applied to the sample between subsequent
+  /// measurements.
+  pub mutations_per_measurement: usize,
+
+  /// Parameters the samples are prepared with. Omitted for samples without
+  /// parameters.
+  #[serde(skip_serializing_if = "::serde_json::Value::is_null")]
+  pub params: ::serde_json::Value,
+
+  /// Index of the point of the parameter sweep, if sweeping.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sweep_point: Option<usize>,
+}
+
 /// An interface to a data sink accepting accumulated expectation values.
 pub trait Exporter {
   /// Performs a single export operation. Note that it does not reset the
   /// accumulated values, which is the job of the simulation engine.
-  fn export(&mut self, measures: &Measures)
+  fn export(&mut self, metadata: &Metadata, measures: &Measures)
      -> Result<(), ExportError>;
+
+  /// Reports a panic caught in the sample or in the measurement function. By
+  /// default, the panic message is only logged.
+  fn report_panic(&mut self, metadata: &Metadata, message: &str)
+     -> Result<(), ExportError> {
+    error!("Caught a panic (seed {}, node {}): {}", metadata.seed,
+           metadata.node_index, message);
+    Ok(())
+  }
 }
 
 /// Keeps a copy of measures. On `export(..)`, merges the reported data and
 /// outputs the accumulated values to stdout.
 pub struct DebugExporter {
-  aggregated: MeasureRegistry,
+  aggregated: Measures,
   creation_timestamp: SystemTime,
 }
 
+impl Default for DebugExporter {
+  fn default() -> DebugExporter {
+    DebugExporter::new()
+  }
+}
+
 impl DebugExporter {
   /// Constructs a new DebugExporter.
   pub fn new() -> DebugExporter {
     DebugExporter {
-      aggregated: MeasureRegistry::new(),
+      aggregated: Measures::new_empty(),
       creation_timestamp: SystemTime::now(),
     }
   }
   
-  /// Format the results in a pretty table.
-  fn pretty_table(measures: &Measures) -> ::prettytable::Table {
+  /// Format the results in a pretty table. Only includes diagnostic measures if
+  /// `diagnostic` is true, and only physical observables otherwise.
+  fn pretty_table(measures: &Measures, diagnostic: bool)
+     -> ::prettytable::Table {
     use ::prettytable::Table;
     use ::prettytable::row::Row;
     use ::prettytable::cell::Cell;
@@ -47,6 +93,9 @@ impl DebugExporter {
       Cell::new_align("RELATIVE UNCERTAINTY", Alignment::CENTER),
     ]));
     for measure in measures.slice() {
+      if measure.is_diagnostic() != diagnostic {
+        continue;
+      }
       let expectation = format!("{}", measure.acc.value());
       let uncertainty = format!("{}", measure.acc.uncertainty());
       let relative_uncertainty =
@@ -64,29 +113,53 @@ impl DebugExporter {
 }
 
 impl Exporter for DebugExporter {
-  fn export(&mut self, measures: &Measures)
+  fn export(&mut self, metadata: &Metadata, measures: &Measures)
      -> Result<(), ExportError> {
-    let mut samples_processed: usize = 0;
     // Merge the reported values to the global accumulated values.
-    for measure in measures.slice() {
-      let measure_idx = match self.aggregated.find(&measure.name) {
-        Some(idx) => idx,
-        None => self.aggregated.register(measure.name.clone()),
-      };
-      self.aggregated.accumulator(measure_idx).merge(measure.acc.clone());
-      samples_processed =
-        self.aggregated.accumulator(measure_idx).num_of_samples() as usize;
-    }
+    self.aggregated.merge(measures);
+    // Diagnostic measures such as the mutation time may be recorded more than
+    // once per sample.
+    let samples_processed = self.aggregated.slice().iter()
+      .filter(|measure| !measure.is_diagnostic())
+      .map(|measure| measure.acc.num_of_samples() as usize)
+      .max()
+      .unwrap_or(0);
 
     // Output the global accumulated values to stdout.
     println!();
     println!("Simulation uptime: {} secs",
              self.creation_timestamp.elapsed().unwrap().as_secs());
     println!("Samples processed: {}", samples_processed);
+    println!("Random seed: {} (node {})", metadata.seed, metadata.node_index);
+    println!("Mutations per measurement: {}",
+             metadata.mutations_per_measurement);
+    if !metadata.params.is_null() {
+      println!("Sample parameters: {}", metadata.params);
+    }
+    if let Some(sweep_point) = metadata.sweep_point {
+      println!("Sweep point: {}", sweep_point);
+    }
     println!("Aggregate values:");
-    DebugExporter::pretty_table(self.aggregated.measures()).printstd();
+    DebugExporter::pretty_table(&self.aggregated, false).printstd();
+    println!("Engine diagnostics:");
+    DebugExporter::pretty_table(&self.aggregated, true).printstd();
     Ok(())
   }
+
+  fn report_panic(&mut self, _metadata: &Metadata, message: &str)
+     -> Result<(), ExportError> {
+    println!();
+    println!("Caught a panic, the offending sample is discarded: {}", message);
+    Ok(())
+  }
+}
+
+/// A panic caught in the simulation, exported by `MongoExporter` as a separate
+/// document. Unlike data points, it contains no measures.
+#[derive(Serialize)]
+struct PanicReport<'a> {
+  metadata: &'a Metadata,
+  panic: &'a str,
 }
 
 /// Exports the measured values to a remote MongoDB collection. Each call to
@@ -106,8 +179,8 @@ pub struct MongoExporter {
 impl MongoExporter {
   /// Constructs a new MongoExporter. Panics on errors.
   /// Example usage:
-  /// ```
-  /// let exporter = MongoExporter::new(
+  /// ```no_run
+  /// let exporter = ergothic::MongoExporter::new(
   ///   /*addr=*/"mongodb://localhost:27017,localhost:27018/",
   ///   /*db_name=*/"ergothic_results",
   ///   /*coll_name=*/"my_simulation",
@@ -128,33 +201,61 @@ impl MongoExporter {
       formatted_addr: format!("{}, db={}, col={}", addr, db_name, coll_name),
     }
   }
+
+  /// Converts the `result` of inserting a document into the result of the
+  /// export.
+  fn check_inserted(&self, result: ::mongodb::Result<InsertOneResult>)
+     -> Result<(), ExportError> {
+    match result {
+      Ok(res) => {
+        if res.acknowledged {
+          if let Some(::mongodb::Bson::ObjectId(id)) = res.inserted_id {
+            info!("Document inserted into {}, obj_id={}",
+                  self.formatted_addr, id.to_hex());
+            Ok(())
+          } else {
+            Err(ExportError(
+                "MongoDB didn't return a new object ID.".to_string()))
+          }
+        } else {
+          Err(ExportError(
+              "MongoDB did not acknowledge the document.".to_string()))
+        }
+      },
+      Err(err) => {
+        Err(ExportError(format!("{:?}", err)))
+      },
+    }
+  }
 }
 
 impl Exporter for MongoExporter {
-  fn export(&mut self, measures: &Measures) -> Result<(), ExportError> {
+  fn export(&mut self, metadata: &Metadata, measures: &Measures)
+     -> Result<(), ExportError> {
     let serialized_data = ::mongodb::to_bson(measures)
         .expect("Serialization error");
+    if let ::mongodb::Bson::Document(mut doc) = serialized_data {
+      doc.insert_bson("metadata".to_string(), ::mongodb::to_bson(metadata)
+                        .expect("Serialization error"));
+      self.check_inserted(
+        self.collection.insert_one(doc, self.write_concern.clone()))
+    } else {
+      panic!("Serialization error: expected Bson::Document, found {}",
+             &serialized_data);
+    }
+  }
+
+  fn report_panic(&mut self, metadata: &Metadata, message: &str)
+     -> Result<(), ExportError> {
+    let report = PanicReport {
+      metadata,
+      panic: message,
+    };
+    let serialized_data = ::mongodb::to_bson(&report)
+        .expect("Serialization error");
     if let ::mongodb::Bson::Document(doc) = serialized_data {
-      match self.collection.insert_one(doc, self.write_concern.clone()) {
-        Ok(res) => {
-          if res.acknowledged {
-            if let Some(::mongodb::Bson::ObjectId(id)) = res.inserted_id {
-              info!("Measurements flushed to {}, obj_id={}",
-                    self.formatted_addr, id.to_hex());
-              Ok(())
-            } else {
-              Err(ExportError(format!(
-                  "MongoDB didn't return a new object ID.")))
-            }
-          } else {
-            Err(ExportError(format!(
-                "MongoDB did not acknowledge measurements.")))
-          }
-        },
-        Err(err) => {
-          Err(ExportError(format!("{:?}", err)))
-        },
-      }
+      self.check_inserted(
+        self.collection.insert_one(doc, self.write_concern.clone()))
     } else {
       panic!("Serialization error: expected Bson::Document, found {}",
              &serialized_data);
@@ -0,0 +1,468 @@
+use ::export::ExportError;
+use ::export::Exporter;
+use ::export::Metadata;
+use ::measure::Measures;
+use ::std::collections::VecDeque;
+use ::std::sync::Arc;
+use ::std::sync::Condvar;
+use ::std::sync::Mutex;
+use ::std::sync::mpsc;
+use ::std::thread::JoinHandle;
+use ::std::time::Duration;
+use ::std::time::Instant;
+
+/// How often the simulation checks on the export thread while waiting for it.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Work handed over to the export thread.
+enum Job {
+  /// Export a data point containing the values flushed up to the given
+  /// flush.
+  Export(Metadata, Measures, usize),
+
+  /// Report a panic caught in the simulation.
+  ReportPanic(Metadata, String),
+}
+
+/// State shared by the simulation and the export thread.
+struct Queue {
+  jobs: VecDeque<Job>,
+
+  /// Maximum number of data points in `jobs`. Values flushed while the queue
+  /// is full are merged into the last waiting data point.
+  capacity: usize,
+
+  /// True while the export thread is working on a job taken from the queue.
+  busy: bool,
+
+  /// Copy of the values being exported by the export thread, so that
+  /// checkpoints can include them without waiting for the export to finish.
+  in_flight: Option<Measures>,
+
+  /// Incremented on every flush.
+  flushes: usize,
+
+  /// The last flush whose values have all been exported. Data points are
+  /// exported in the order of their flushes, even when they are retried or
+  /// merged.
+  delivered: usize,
+
+  /// After a failed export, the data point is put back to the front of the
+  /// queue, and retried on the first flush after the given one.
+  retry_after: Option<usize>,
+
+  /// Set once the simulation stops. Failed exports are then retried every
+  /// `retry_interval`.
+  closing: bool,
+
+  /// Set once the simulation gives up on the remaining exports.
+  abandoned: bool,
+}
+
+impl Queue {
+  /// Constructs an empty queue holding at most `capacity` data points.
+  fn new(capacity: usize) -> Queue {
+    Queue {
+      jobs: VecDeque::new(),
+      capacity,
+      busy: false,
+      in_flight: None,
+      flushes: 0,
+      delivered: 0,
+      retry_after: None,
+      closing: false,
+      abandoned: false,
+    }
+  }
+
+  /// Adds a data point with the values flushed up to `flush` to the back of
+  /// the queue, or to the front if it is being retried. If the queue is full,
+  /// the values are merged into the neighbouring data point instead.
+  fn push(&mut self, metadata: Metadata, measures: Measures, flush: usize,
+          front: bool) {
+    let waiting = self.jobs.iter()
+      .filter(|job| matches!(job, Job::Export(..)))
+      .count();
+    if waiting >= self.capacity {
+      let neighbour = if front {
+        self.jobs.iter_mut().find(|job| matches!(job, Job::Export(..)))
+      } else {
+        self.jobs.iter_mut().rev().find(|job| matches!(job, Job::Export(..)))
+      };
+      if let Some(Job::Export(_, waiting, waiting_flush)) = neighbour {
+        waiting.merge(&measures);
+        *waiting_flush = ::std::cmp::max(*waiting_flush, flush);
+        return;
+      }
+    }
+    let job = Job::Export(metadata, measures, flush);
+    if front {
+      self.jobs.push_front(job);
+    } else {
+      self.jobs.push_back(job);
+    }
+  }
+
+  /// Takes the next job for the export thread, unless a failed export waits
+  /// for the next flush to be retried.
+  fn take(&mut self) -> Option<Job> {
+    let retry_due = self.closing ||
+      self.retry_after.map_or(true, |flush| self.flushes > flush);
+    if !retry_due {
+      return None;
+    }
+    let job = self.jobs.pop_front()?;
+    self.busy = true;
+    if let Job::Export(_, ref measures, _) = job {
+      self.in_flight = Some(measures.clone());
+    }
+    Some(job)
+  }
+
+  /// Records the outcome of exporting the data point taken with `take()`.
+  /// Failed data points are put back to the front of the queue and retried
+  /// after the next flush.
+  fn finish_export(&mut self, metadata: Metadata, measures: Measures,
+                   flush: usize, failed: bool) {
+    self.busy = false;
+    self.in_flight = None;
+    if failed {
+      self.retry_after = Some(self.flushes);
+      self.push(metadata, measures, flush, true);
+    } else {
+      self.retry_after = None;
+      self.delivered = ::std::cmp::max(self.delivered, flush);
+    }
+  }
+
+  /// Merges the values of the data points waiting in the queue and of the
+  /// one being exported.
+  fn pending(&self) -> Measures {
+    let mut pending = Measures::new_empty();
+    for job in self.jobs.iter() {
+      if let Job::Export(_, ref measures, _) = *job {
+        pending.merge(measures);
+      }
+    }
+    if let Some(ref in_flight) = self.in_flight {
+      pending.merge(in_flight);
+    }
+    pending
+  }
+}
+
+/// Exports the data points in a background thread, so that slow or failing
+/// exports don't stall the simulation. Data points are queued, and retried
+/// until they are exported.
+pub struct ExportThread {
+  shared: Arc<(Mutex<Queue>, Condvar)>,
+  results: mpsc::Receiver<Result<(), ExportError>>,
+  handle: Option<JoinHandle<()>>,
+}
+
+impl ExportThread {
+  /// Starts a thread exporting the data points with the `exporter`. At most
+  /// `capacity` data points wait in the queue. Once the simulation stops,
+  /// failed exports are retried every `retry_interval`.
+  pub fn start(exporter: Box<dyn Exporter + Send>, capacity: usize,
+               retry_interval: Duration) -> ExportThread {
+    assert!(capacity > 0, "The export queue should hold at least one data \
+                           point.");
+    let shared = Arc::new((Mutex::new(Queue::new(capacity)), Condvar::new()));
+    let (sender, results) = mpsc::channel();
+    let worker_shared = shared.clone();
+    let handle = ::std::thread::Builder::new()
+      .name("ergothic-export".to_string())
+      .spawn(move || work(exporter, &worker_shared, &sender, retry_interval))
+      .expect("Failed to spawn the export thread.");
+    ExportThread {
+      shared,
+      results,
+      handle: Some(handle),
+    }
+  }
+
+  /// Queues a data point for export. Never blocks on the exporter.
+  pub fn export(&mut self, metadata: &Metadata, measures: &Measures) {
+    self.check_alive();
+    let (ref lock, ref cvar) = *self.shared;
+    let mut queue = lock.lock().unwrap();
+    queue.flushes += 1;
+    let flush = queue.flushes;
+    queue.push(metadata.clone(), measures.clone(), flush, false);
+    cvar.notify_all();
+  }
+
+  /// Queues a report of a panic caught in the simulation. Failed reports are
+  /// not retried.
+  pub fn report_panic(&mut self, metadata: &Metadata, message: &str) {
+    self.check_alive();
+    let (ref lock, ref cvar) = *self.shared;
+    lock.lock().unwrap().jobs
+      .push_back(Job::ReportPanic(metadata.clone(), message.to_string()));
+    cvar.notify_all();
+  }
+
+  /// Gives the results of the exports finished since the last call.
+  pub fn results(&mut self) -> Vec<Result<(), ExportError>> {
+    self.check_alive();
+    self.results.try_iter().collect()
+  }
+
+  /// Gives the values which haven't been exported yet: those waiting in the
+  /// queue and those being exported right now. Doesn't wait for the export
+  /// thread.
+  pub fn pending(&mut self) -> Measures {
+    self.check_alive();
+    let (ref lock, _) = *self.shared;
+    lock.lock().unwrap().pending()
+  }
+
+  /// Gives the number of flushes so far, and the last flush whose values have
+  /// all been exported.
+  pub fn progress(&mut self) -> (usize, usize) {
+    self.check_alive();
+    let (ref lock, _) = *self.shared;
+    let queue = lock.lock().unwrap();
+    (queue.flushes, queue.delivered)
+  }
+
+  /// Waits until all of the queued data points are exported or the `deadline`
+  /// passes. Returns the values which haven't been exported by then, if any.
+  /// The export thread gives up on them.
+  pub fn close(&mut self, deadline: Instant) -> Option<Measures> {
+    let undelivered = {
+      let (ref lock, ref cvar) = *self.shared;
+      let mut queue = lock.lock().unwrap();
+      queue.closing = true;
+      cvar.notify_all();
+      loop {
+        if !queue.busy && queue.jobs.is_empty() {
+          break None;
+        }
+        let now = Instant::now();
+        if now >= deadline || self.has_stopped() {
+          queue.abandoned = true;
+          cvar.notify_all();
+          break Some(queue.pending());
+        }
+        let timeout = ::std::cmp::min(deadline - now, WAIT_POLL_INTERVAL);
+        queue = cvar.wait_timeout(queue, timeout).unwrap().0;
+      }
+    };
+    self.check_alive();
+    if undelivered.is_none() {
+      if let Some(handle) = self.handle.take() {
+        handle.join().ok();
+      }
+    }
+    undelivered
+  }
+
+  /// Returns true if the export thread has returned or panicked.
+  fn has_stopped(&self) -> bool {
+    self.handle.as_ref().map_or(false, |h| h.is_finished())
+  }
+
+  /// Propagates the panic of the export thread, if it has panicked.
+  fn check_alive(&mut self) {
+    if !self.has_stopped() {
+      return;
+    }
+    if let Err(err) = self.handle.take().unwrap().join() {
+      ::std::panic::resume_unwind(err);
+    }
+  }
+}
+
+// Stops the export thread if the simulation unwinds without closing it.
+impl Drop for ExportThread {
+  fn drop(&mut self) {
+    let (ref lock, ref cvar) = *self.shared;
+    if let Ok(mut queue) = lock.lock() {
+      queue.abandoned = true;
+    }
+    cvar.notify_all();
+  }
+}
+
+/// Body of the export thread. Takes the jobs from the queue and sends the
+/// results of the exports back to the simulation.
+fn work(mut exporter: Box<dyn Exporter + Send>,
+        shared: &(Mutex<Queue>, Condvar),
+        results: &mpsc::Sender<Result<(), ExportError>>,
+        retry_interval: Duration) {
+  let (ref lock, ref cvar) = *shared;
+  loop {
+    let job = {
+      let mut queue = lock.lock().unwrap();
+      loop {
+        if queue.abandoned {
+          return;
+        }
+        if let Some(job) = queue.take() {
+          break job;
+        }
+        if queue.closing {
+          return;
+        }
+        queue = cvar.wait(queue).unwrap();
+      }
+    };
+    match job {
+      Job::Export(metadata, measures, flush) => {
+        let result = exporter.export(&metadata, &measures);
+        let failed = result.is_err();
+        // The simulation may have stopped listening.
+        results.send(result).ok();
+        let mut queue = lock.lock().unwrap();
+        queue.finish_export(metadata, measures, flush, failed);
+        let closing = queue.closing;
+        cvar.notify_all();
+        drop(queue);
+        if failed && closing {
+          ::std::thread::sleep(retry_interval);
+        }
+      },
+      Job::ReportPanic(metadata, message) => {
+        if let Err(ExportError(err)) =
+               exporter.report_panic(&metadata, &message) {
+          error!("Failed to report the panic: {:?}", err);
+        }
+        lock.lock().unwrap().busy = false;
+        cvar.notify_all();
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Job;
+  use super::Queue;
+  use ::export::Metadata;
+  use ::measure::Measures;
+
+  fn metadata() -> Metadata {
+    Metadata {
+      seed: 0,
+      node_index: 0,
+      mutations_per_measurement: 1,
+      params: ::serde_json::Value::Null,
+      sweep_point: None,
+    }
+  }
+
+  /// A data point with `samples` values of the measure `x`.
+  fn data_point(samples: usize) -> Measures {
+    let mut measures = Measures::new_empty();
+    let idx = measures.find_or_register("x");
+    for _ in 0..samples {
+      measures.accumulate(idx, 1.0);
+    }
+    measures
+  }
+
+  /// Number of samples in each of the data points waiting in the queue.
+  fn waiting(queue: &Queue) -> Vec<f64> {
+    queue.jobs.iter()
+      .filter_map(|job| match *job {
+        Job::Export(_, ref measures, _) => Some(samples(measures)),
+        Job::ReportPanic(..) => None,
+      })
+      .collect()
+  }
+
+  fn samples(measures: &Measures) -> f64 {
+    let idx = measures.find("x").expect("Measure x is missing.");
+    measures.get(idx).acc.num_of_samples()
+  }
+
+  #[test]
+  fn full_queue_merges_into_last_data_point() {
+    let mut queue = Queue::new(2);
+    queue.push(metadata(), data_point(1), 1, false);
+    queue.push(metadata(), data_point(2), 2, false);
+    queue.push(metadata(), data_point(3), 3, false);
+    assert_eq!(waiting(&queue), vec![1.0, 5.0]);
+  }
+
+  #[test]
+  fn panic_reports_dont_take_capacity() {
+    let mut queue = Queue::new(1);
+    queue.jobs.push_back(Job::ReportPanic(metadata(), "panic".to_string()));
+    queue.push(metadata(), data_point(1), 1, false);
+    queue.push(metadata(), data_point(2), 2, false);
+    assert_eq!(queue.jobs.len(), 2);
+    assert_eq!(waiting(&queue), vec![3.0]);
+  }
+
+  #[test]
+  fn failed_export_is_retried_after_next_flush() {
+    let mut queue = Queue::new(2);
+    queue.flushes += 1;
+    queue.push(metadata(), data_point(1), 1, false);
+    let (exported, measures, flush) = match queue.take() {
+      Some(Job::Export(exported, measures, flush)) => (exported, measures, flush),
+      _ => panic!("Expected a data point to export."),
+    };
+    queue.finish_export(exported, measures, flush, true);
+    assert_eq!(waiting(&queue), vec![1.0]);
+    assert!(queue.take().is_none(), "Retried before the next flush.");
+
+    queue.flushes += 1;
+    queue.push(metadata(), data_point(2), 2, false);
+    match queue.take() {
+      Some(Job::Export(_, ref measures, _)) => assert_eq!(samples(measures), 1.0),
+      _ => panic!("Expected the failed data point first."),
+    }
+  }
+
+  #[test]
+  fn failed_export_merges_into_full_queue() {
+    let mut queue = Queue::new(1);
+    queue.push(metadata(), data_point(1), 1, false);
+    let job = queue.take();
+    queue.push(metadata(), data_point(2), 2, false);
+    if let Some(Job::Export(exported, measures, flush)) = job {
+      queue.finish_export(exported, measures, flush, true);
+    }
+    assert_eq!(waiting(&queue), vec![3.0]);
+  }
+
+  #[test]
+  fn pending_includes_data_point_in_flight() {
+    let mut queue = Queue::new(2);
+    queue.push(metadata(), data_point(1), 1, false);
+    queue.push(metadata(), data_point(2), 2, false);
+    let job = queue.take();
+    assert!(queue.busy);
+    assert_eq!(samples(&queue.pending()), 3.0);
+    if let Some(Job::Export(exported, measures, flush)) = job {
+      queue.finish_export(exported, measures, flush, false);
+    }
+    assert_eq!(samples(&queue.pending()), 2.0);
+  }
+
+  #[test]
+  fn delivered_follows_merged_and_retried_data_points() {
+    let mut queue = Queue::new(2);
+    for flush in 1..4 {
+      queue.flushes = flush;
+      queue.push(metadata(), data_point(flush), flush, false);
+    }
+    let finish = |queue: &mut Queue, failed| match queue.take() {
+      Some(Job::Export(exported, measures, flush)) =>
+        queue.finish_export(exported, measures, flush, failed),
+      _ => panic!("Expected a data point to export."),
+    };
+    finish(&mut queue, false);
+    assert_eq!(queue.delivered, 1);
+    // Flushes 2 and 3 were merged into a single data point.
+    finish(&mut queue, true);
+    assert_eq!(queue.delivered, 1);
+    queue.flushes += 1;
+    finish(&mut queue, false);
+    assert_eq!(queue.delivered, 3);
+  }
+}
@@ -0,0 +1,480 @@
+//! The field is described by the `Field` trait, which gives its real degrees
+//! of freedom, its action and the force acting on it. Wrapped in `HmcSample`,
+//! the field becomes a sample whose every mutation is a single HMC
+//! trajectory: the momenta conjugate to the field are drawn from the Gaussian
+//! distribution, the equations of motion are integrated numerically, and the
+//! resulting configuration is accepted or rejected according to the change
+//! of the Hamiltonian ΔH. The acceptance rate, ΔH, exp(-ΔH) and the fraction
+//! of diverged trajectories are recorded as diagnostic measures. The mean of
+//! exp(-ΔH) should be consistent with one, which is a useful check of the
+//! force.
+//!
+//! ```no_run
+//! # extern crate ergothic;
+//! # #[macro_use] extern crate serde_derive;
+//! use ergothic::hmc::{Field, HmcSample, Integrator, Trajectory};
+//!
+//! // Free scalar field on a periodic chain of sites.
+//! #[derive(Serialize, Deserialize)]
+//! struct Scalar {
+//!   phi: Vec<f64>,
+//! }
+//!
+//! impl Field for Scalar {
+//!   type Params = ();
+//!
+//!   fn prepare(_params: &(), _rng: &mut ergothic::Rng) -> Scalar {
+//!     Scalar { phi: vec![0.0; 100] }
+//!   }
+//!
+//!   fn trajectory(_params: &()) -> Trajectory {
+//!     Trajectory {
+//!       integrator: Integrator::Omelyan,
+//!       steps: 10,
+//!       step_size: 0.1,
+//!     }
+//!   }
+//!
+//!   fn coordinates(&self) -> &[f64] {
+//!     &self.phi
+//!   }
+//!
+//!   fn coordinates_mut(&mut self) -> &mut [f64] {
+//!     &mut self.phi
+//!   }
+//!
+//!   fn action(&self) -> f64 {
+//!     let n = self.phi.len();
+//!     (0..n).map(|i| (self.phi[(i + 1) % n] - self.phi[i]).powi(2) / 2.0
+//!                      + self.phi[i].powi(2) / 2.0).sum()
+//!   }
+//!
+//!   fn force(&self, force: &mut [f64]) {
+//!     let n = self.phi.len();
+//!     for i in 0..n {
+//!       let laplacian = self.phi[(i + 1) % n] + self.phi[(i + n - 1) % n]
+//!                       - 2.0 * self.phi[i];
+//!       force[i] = laplacian - self.phi[i];
+//!     }
+//!   }
+//! }
+//!
+//! fn main() {
+//!   let sim = ergothic::Simulation::new("Free field");
+//!   sim.run(|s: &HmcSample<Scalar>, _ms| {
+//!     // Measure the field s.field.
+//!   });
+//! }
+//! ```
+
+use ::metropolis::Acceptance;
+use ::metropolis::accept;
+use ::rand::Rng as RandRng;
+use ::rand::distributions::StandardNormal;
+use ::rng::Rng;
+use ::serde::Serialize;
+use ::serde::de::DeserializeOwned;
+use ::simulation::ParameterizedSample;
+use ::statistics::UpdateStats;
+
+/// The parameter of the Omelyan integrator minimizing the norm of its
+/// leading error term.
+const OMELYAN_LAMBDA: f64 = 0.1931833275037836;
+
+/// A field evolved by Hybrid Monte-Carlo. Only the action and the force have
+/// to be implemented: the momenta, the integration and the accept-reject step
+/// are taken care of by `HmcSample`.
+pub trait Field: Sized {
+  /// Parameters the field is prepared with. See
+  /// `ParameterizedSample::Params`.
+  type Params: Serialize + DeserializeOwned + Default + Sync;
+
+  /// Creates a new field configuration.
+  fn prepare(params: &Self::Params, rng: &mut Rng) -> Self;
+
+  /// Gives the settings of the molecular dynamics trajectories. The default
+  /// implementation gives `Trajectory::default()`.
+  fn trajectory(_params: &Self::Params) -> Trajectory {
+    Trajectory::default()
+  }
+
+  /// Gives the real degrees of freedom of the field. They must describe the
+  /// configuration completely: rejected trajectories restore them, and
+  /// nothing else.
+  fn coordinates(&self) -> &[f64];
+
+  /// Gives the real degrees of freedom of the field for modification.
+  fn coordinates_mut(&mut self) -> &mut [f64];
+
+  /// Gives the action of the field configuration.
+  fn action(&self) -> f64;
+
+  /// Writes the force acting on the field, which is minus the derivative of
+  /// the action with respect to the `i`-th coordinate, into `force[i]`.
+  fn force(&self, force: &mut [f64]);
+}
+
+/// Numerical integrators of the equations of motion. Both are reversible and
+/// area-preserving, as required for the detailed balance.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Integrator {
+  /// The leapfrog integrator, evaluating the force once per step.
+  Leapfrog,
+
+  /// The second-order minimum norm integrator by Omelyan, Mryglod and
+  /// Folk. Evaluates the force twice per step, but its error is about ten
+  /// times smaller than that of the leapfrog, which allows for larger steps.
+  Omelyan,
+}
+
+/// Settings of a molecular dynamics trajectory. The length of the trajectory
+/// is `steps * step_size`.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Trajectory {
+  /// Integrator of the equations of motion.
+  pub integrator: Integrator,
+
+  /// Number of integration steps.
+  pub steps: usize,
+
+  /// Size of a single integration step. Larger steps give longer
+  /// trajectories, but also larger ΔH and lower acceptance rates.
+  pub step_size: f64,
+}
+
+impl Default for Trajectory {
+  /// Trajectories of unit length, made of 10 leapfrog steps.
+  fn default() -> Trajectory {
+    Trajectory {
+      integrator: Integrator::Leapfrog,
+      steps: 10,
+      step_size: 0.1,
+    }
+  }
+}
+
+impl Trajectory {
+  /// Evolves the `field` and the conjugate `momenta` along the trajectory.
+  fn integrate<F: Field>(&self, field: &mut F, momenta: &mut [f64]) {
+    assert!(self.steps > 0, "A trajectory should make at least one step.");
+    let mut force = vec![0.0; momenta.len()];
+    let eps = self.step_size;
+    match self.integrator {
+      Integrator::Leapfrog => {
+        kick(field, momenta, &mut force, eps / 2.0);
+        for step in 0..self.steps {
+          drift(field, momenta, eps);
+          let last = step + 1 == self.steps;
+          kick(field, momenta, &mut force, if last { eps / 2.0 } else { eps });
+        }
+      },
+      Integrator::Omelyan => {
+        // The closing kick of a step and the opening kick of the next one act
+        // at the same position, so they are merged into a single kick.
+        kick(field, momenta, &mut force, OMELYAN_LAMBDA * eps);
+        for step in 0..self.steps {
+          drift(field, momenta, eps / 2.0);
+          kick(field, momenta, &mut force, (1.0 - 2.0 * OMELYAN_LAMBDA) * eps);
+          drift(field, momenta, eps / 2.0);
+          let last = step + 1 == self.steps;
+          let lambda = if last { OMELYAN_LAMBDA } else { 2.0 * OMELYAN_LAMBDA };
+          kick(field, momenta, &mut force, lambda * eps);
+        }
+      },
+    }
+  }
+}
+
+/// Updates the `momenta` by the force acting on the `field` over the time
+/// `eps`. The `force` buffer holds one value per coordinate.
+fn kick<F: Field>(field: &F, momenta: &mut [f64], force: &mut [f64],
+                  eps: f64) {
+  field.force(force);
+  for (p, f) in momenta.iter_mut().zip(force.iter()) {
+    *p += eps * f;
+  }
+}
+
+/// Moves the `field` along the `momenta` over the time `eps`.
+fn drift<F: Field>(field: &mut F, momenta: &[f64], eps: f64) {
+  let coordinates = field.coordinates_mut();
+  assert_eq!(coordinates.len(), momenta.len(),
+             "The number of coordinates of the field has changed.");
+  for (x, p) in coordinates.iter_mut().zip(momenta.iter()) {
+    *x += eps * p;
+  }
+}
+
+/// Kinetic energy of the `momenta`, all of unit mass.
+fn kinetic_energy(momenta: &[f64]) -> f64 {
+  momenta.iter().map(|p| p * p).sum::<f64>() / 2.0
+}
+
+/// A sample evolving the `field` with Hybrid Monte-Carlo. Every mutation is a
+/// single trajectory. The following diagnostic measures are recorded:
+///
+/// * `__ergothic/hmc/acceptance` – the acceptance rate of the trajectories.
+/// * `__ergothic/hmc/delta_h` – the change of the Hamiltonian along a
+///   trajectory.
+/// * `__ergothic/hmc/exp_minus_delta_h` – exp(-ΔH), whose mean should be
+///   consistent with one. Diverged trajectories count as zero.
+/// * `__ergothic/hmc/divergences` – the fraction of trajectories whose ΔH is
+///   not finite. Such trajectories are always rejected.
+#[derive(Serialize, Deserialize)]
+pub struct HmcSample<F> {
+  /// The field configuration.
+  pub field: F,
+
+  /// Settings of the trajectories.
+  pub trajectory: Trajectory,
+
+  acceptance: Acceptance,
+
+  /// Values of ΔH since the statistics were last reported.
+  #[serde(default)]
+  delta_h: Vec<f64>,
+}
+
+impl<F: Field> HmcSample<F> {
+  /// Runs a single trajectory. Returns true if its end point is accepted.
+  /// Otherwise, the field is restored.
+  pub fn run_trajectory(&mut self, rng: &mut Rng) -> bool {
+    let old_coordinates = self.field.coordinates().to_vec();
+    let mut momenta: Vec<f64> = (0..old_coordinates.len())
+      .map(|_| rng.sample(StandardNormal))
+      .collect();
+    let old_h = kinetic_energy(&momenta) + self.field.action();
+    self.trajectory.integrate(&mut self.field, &mut momenta);
+    let delta_h = kinetic_energy(&momenta) + self.field.action() - old_h;
+    self.delta_h.push(delta_h);
+    if !delta_h.is_finite() {
+      warn!("The trajectory diverged with ΔH = {}, consider reducing the \
+             step size.", delta_h);
+    }
+    let accepted = delta_h.is_finite() && accept(delta_h, rng);
+    self.acceptance.record(accepted);
+    if !accepted {
+      self.field.coordinates_mut().copy_from_slice(&old_coordinates);
+    }
+    accepted
+  }
+}
+
+impl<F: Field> ParameterizedSample for HmcSample<F> {
+  type Params = F::Params;
+
+  fn prepare(params: &F::Params, rng: &mut Rng) -> HmcSample<F> {
+    HmcSample {
+      field: F::prepare(params, rng),
+      trajectory: F::trajectory(params),
+      acceptance: Acceptance::new(),
+      delta_h: Vec::new(),
+    }
+  }
+
+  fn mutate(&mut self, rng: &mut Rng) {
+    self.run_trajectory(rng);
+  }
+
+  fn action(&self) -> Option<f64> {
+    Some(self.field.action())
+  }
+
+  fn report_statistics(&mut self, stats: &mut UpdateStats) {
+    for delta_h in self.delta_h.drain(..) {
+      if delta_h.is_finite() {
+        stats.record("hmc/delta_h", delta_h);
+        stats.record("hmc/exp_minus_delta_h", (-delta_h).exp());
+        stats.record("hmc/divergences", 0.0);
+      } else {
+        // ΔH = +∞ in the limit, so exp(-ΔH) vanishes.
+        stats.record("hmc/exp_minus_delta_h", 0.0);
+        stats.record("hmc/divergences", 1.0);
+      }
+    }
+    self.acceptance.report("hmc/acceptance", stats);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Field;
+  use super::HmcSample;
+  use super::Integrator;
+  use super::Trajectory;
+  use super::kinetic_energy;
+  use ::measure::Measures;
+  use ::rand::Rng as RandRng;
+  use ::rng::Rng;
+  use ::rng::chain_rng;
+  use ::simulation::ParameterizedSample;
+  use ::statistics::UpdateStats;
+  use ::std::cell::Cell;
+
+  thread_local! {
+    /// Number of force evaluations in the current thread.
+    static FORCES: Cell<usize> = const { Cell::new(0) };
+  }
+
+  /// Free massive scalar field on a periodic chain of 16 sites.
+  #[derive(Serialize, Deserialize)]
+  struct Scalar {
+    phi: Vec<f64>,
+  }
+
+  impl Field for Scalar {
+    type Params = ();
+
+    fn prepare(_params: &(), rng: &mut Rng) -> Scalar {
+      Scalar {
+        phi: (0..16).map(|_| rng.gen_range(-1.0, 1.0)).collect(),
+      }
+    }
+
+    fn coordinates(&self) -> &[f64] {
+      &self.phi
+    }
+
+    fn coordinates_mut(&mut self) -> &mut [f64] {
+      &mut self.phi
+    }
+
+    fn action(&self) -> f64 {
+      let n = self.phi.len();
+      (0..n).map(|i| (self.phi[(i + 1) % n] - self.phi[i]).powi(2) / 2.0
+                       + self.phi[i].powi(2) / 2.0).sum()
+    }
+
+    fn force(&self, force: &mut [f64]) {
+      FORCES.with(|forces| forces.set(forces.get() + 1));
+      let n = self.phi.len();
+      for (i, f) in force.iter_mut().enumerate() {
+        *f = self.phi[(i + 1) % n] + self.phi[(i + n - 1) % n]
+             - 3.0 * self.phi[i];
+      }
+    }
+  }
+
+  fn trajectory(integrator: Integrator, step_size: f64) -> Trajectory {
+    Trajectory {
+      integrator,
+      steps: (1.0 / step_size).round() as usize,
+      step_size,
+    }
+  }
+
+  /// Integrates a unit-length trajectory from a random point, and gives the
+  /// change of the Hamiltonian.
+  fn delta_h(integrator: Integrator, step_size: f64) -> f64 {
+    let mut rng = chain_rng(1, 0, 0);
+    let mut field = Scalar::prepare(&(), &mut rng);
+    let mut momenta: Vec<f64> = (0..16).map(|_| rng.gen_range(-1.0, 1.0))
+      .collect();
+    let old_h = kinetic_energy(&momenta) + field.action();
+    trajectory(integrator, step_size).integrate(&mut field, &mut momenta);
+    kinetic_energy(&momenta) + field.action() - old_h
+  }
+
+  #[test]
+  fn integrators_are_reversible() {
+    for &integrator in [Integrator::Leapfrog, Integrator::Omelyan].iter() {
+      let mut rng = chain_rng(1, 0, 0);
+      let mut field = Scalar::prepare(&(), &mut rng);
+      let old_phi = field.phi.clone();
+      let mut momenta: Vec<f64> = (0..16).map(|_| rng.gen_range(-1.0, 1.0))
+        .collect();
+      let old_momenta = momenta.clone();
+      let trajectory = trajectory(integrator, 0.1);
+      trajectory.integrate(&mut field, &mut momenta);
+      for p in momenta.iter_mut() {
+        *p = -*p;
+      }
+      trajectory.integrate(&mut field, &mut momenta);
+      for (phi, old_phi) in field.phi.iter().zip(old_phi.iter()) {
+        assert!((phi - old_phi).abs() < 1e-12, "{:?}", integrator);
+      }
+      for (p, old_p) in momenta.iter().zip(old_momenta.iter()) {
+        assert!((p + old_p).abs() < 1e-12, "{:?}", integrator);
+      }
+    }
+  }
+
+  #[test]
+  fn leapfrog_conserves_energy_to_second_order() {
+    let coarse = delta_h(Integrator::Leapfrog, 0.1).abs();
+    let fine = delta_h(Integrator::Leapfrog, 0.05).abs();
+    assert!(coarse < 0.1, "ΔH = {}.", coarse);
+    // The error of the energy scales as the square of the step size.
+    assert!(coarse / fine > 3.0 && coarse / fine < 5.0,
+            "ΔH = {} and {}.", coarse, fine);
+  }
+
+  #[test]
+  fn omelyan_is_more_accurate_than_leapfrog() {
+    let leapfrog = delta_h(Integrator::Leapfrog, 0.1).abs();
+    let omelyan = delta_h(Integrator::Omelyan, 0.1).abs();
+    assert!(omelyan < leapfrog / 5.0, "ΔH = {} and {}.", omelyan, leapfrog);
+  }
+
+  #[test]
+  fn integrators_evaluate_force_once_or_twice_per_step() {
+    let expected = [(Integrator::Leapfrog, 11), (Integrator::Omelyan, 21)];
+    for &(integrator, forces) in expected.iter() {
+      let mut rng = chain_rng(1, 0, 0);
+      let mut field = Scalar::prepare(&(), &mut rng);
+      let mut momenta = vec![0.0; 16];
+      FORCES.with(|forces| forces.set(0));
+      trajectory(integrator, 0.1).integrate(&mut field, &mut momenta);
+      assert_eq!(FORCES.with(|forces| forces.get()), forces, "{:?}",
+                 integrator);
+    }
+  }
+
+  /// Gives the statistics reported by the `sample`.
+  fn report<F: Field>(sample: &mut HmcSample<F>) -> Measures {
+    let mut measures = Measures::new_empty();
+    sample.report_statistics(&mut UpdateStats::new(&mut measures));
+    measures
+  }
+
+  /// Gives the mean value of the diagnostic measure `__ergothic/hmc/<name>`.
+  fn mean(measures: &Measures, name: &str) -> f64 {
+    let idx = measures.find(&format!("__ergothic/hmc/{}", name)).unwrap();
+    measures.get(idx).acc.value()
+  }
+
+  #[test]
+  fn trajectories_are_accepted_and_reported() {
+    let mut rng = chain_rng(1, 0, 0);
+    let mut sample = HmcSample::<Scalar>::prepare(&(), &mut rng);
+    for _ in 0..1000 {
+      sample.mutate(&mut rng);
+    }
+    let measures = report(&mut sample);
+    let acceptance = mean(&measures, "acceptance");
+    assert!(acceptance > 0.9, "Acceptance rate {}.", acceptance);
+    let exp_minus_delta_h = mean(&measures, "exp_minus_delta_h");
+    assert!((exp_minus_delta_h - 1.0).abs() < 0.05,
+            "<exp(-ΔH)> = {}.", exp_minus_delta_h);
+    assert_eq!(mean(&measures, "divergences"), 0.0);
+  }
+
+  #[test]
+  fn diverged_trajectories_are_rejected_and_reported() {
+    let mut rng = chain_rng(1, 0, 0);
+    let mut sample = HmcSample::<Scalar>::prepare(&(), &mut rng);
+    let old_phi = sample.field.phi.clone();
+    // The integration is unstable for such steps, and the field blows up.
+    sample.trajectory = Trajectory {
+      integrator: Integrator::Leapfrog,
+      steps: 1000,
+      step_size: 10.0,
+    };
+    assert!(!sample.run_trajectory(&mut rng));
+    assert_eq!(sample.field.phi, old_phi);
+    let measures = report(&mut sample);
+    assert_eq!(mean(&measures, "divergences"), 1.0);
+    assert_eq!(mean(&measures, "exp_minus_delta_h"), 0.0);
+    assert_eq!(mean(&measures, "acceptance"), 0.0);
+  }
+}
@@ -0,0 +1,91 @@
+use ::export::ExportError;
+use ::export::Metadata;
+use ::measure::Measures;
+use ::stop::Results;
+use ::std::sync::Arc;
+
+/// A hook called on every sample right after it is thermalized. Shared by the
+/// chains, which may run in separate threads.
+pub type ThermalizeHook<S> = Arc<dyn Fn(&mut S) + Send + Sync>;
+
+/// A hook called with the metadata of the run when it starts.
+pub type StartHook = Box<dyn FnMut(&Metadata)>;
+
+/// A hook called with the values about to be exported.
+pub type FlushHook = Box<dyn FnMut(&Measures)>;
+
+/// A hook called with the result of an export.
+pub type ExportHook = Box<dyn FnMut(&Result<(), ExportError>)>;
+
+/// A hook called with the results once the simulation stops.
+pub type StopHook = Box<dyn FnMut(&Results)>;
+
+/// Callbacks invoked at well-defined points of the simulation. Except for the
+/// `after_thermalize` hooks, all hooks are called in the thread flushing the
+/// measures, in the order of registration.
+pub struct Hooks<S> {
+  /// Called with the metadata of the run when the simulation starts, before
+  /// the samples are prepared.
+  pub on_start: Vec<StartHook>,
+
+  /// Called whenever a sample has been prepared and thermalized, or
+  /// thermalized again on refresh. Not called for samples restored from a
+  /// checkpoint.
+  pub after_thermalize: Vec<ThermalizeHook<S>>,
+
+  /// Called with the values about to be handed over to the export thread on
+  /// every flush.
+  pub before_flush: Vec<FlushHook>,
+
+  /// Called with the result of every attempt to export the values. Exports
+  /// run in a background thread, so the results are only seen on the next
+  /// flush, or when the simulation stops.
+  pub after_flush: Vec<ExportHook>,
+
+  /// Called with the results once the simulation stops and the remaining
+  /// values have been flushed.
+  pub on_stop: Vec<StopHook>,
+}
+
+// Implemented by hand, since deriving would require `S: Default`.
+impl<S> Default for Hooks<S> {
+  fn default() -> Hooks<S> {
+    Hooks {
+      on_start: Vec::new(),
+      after_thermalize: Vec::new(),
+      before_flush: Vec::new(),
+      after_flush: Vec::new(),
+      on_stop: Vec::new(),
+    }
+  }
+}
+
+impl<S> Hooks<S> {
+  /// Calls the `on_start` hooks.
+  pub fn start(&mut self, metadata: &Metadata) {
+    for hook in self.on_start.iter_mut() {
+      hook(metadata);
+    }
+  }
+
+  /// Calls the `before_flush` hooks.
+  pub fn before_flush(&mut self, measures: &Measures) {
+    for hook in self.before_flush.iter_mut() {
+      hook(measures);
+    }
+  }
+
+  /// Calls the `after_flush` hooks.
+  pub fn after_flush(&mut self, result: &Result<(), ExportError>) {
+    for hook in self.after_flush.iter_mut() {
+      hook(result);
+    }
+  }
+
+  /// Calls the `on_stop` hooks.
+  pub fn stop(&mut self, results: &Results) {
+    for hook in self.on_stop.iter_mut() {
+      hook(results);
+    }
+  }
+}
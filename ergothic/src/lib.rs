@@ -12,10 +12,13 @@
 
 extern crate argparse;
 extern crate bson;
+extern crate ctrlc;
 extern crate mongodb;
+extern crate num_complex;
 extern crate prettytable;
 extern crate rand;
 extern crate serde;
+extern crate serde_json;
 extern crate simple_logger;
 
 #[macro_use]
@@ -26,18 +29,49 @@ extern crate serde_derive;
 
 extern crate structopt;
 
+/// Programmatic configuration of the simulation engine, as an alternative to
+/// the command line.
+mod builder;
+
+/// Checkpointing the state of the simulation to a local file and resuming
+/// from it.
+mod checkpoint;
+
 /// Utilities related to accumulating mean values and statistical errors for
 /// physical observables measured on sample configurations drawn from the
 /// ergodic distribution.
 mod accumulate;
 
+/// Adaptive thermalization, which detects when the tracked observables become
+/// stationary.
+mod equilibrate;
+
 /// Exporters provide interfaces for sending the measured expectation values to
 /// different types of data sinks.
 mod export;
 
+/// Callbacks invoked at well-defined points of the simulation.
+mod hooks;
+
+/// Background thread exporting the data points, so that slow exports don't
+/// stall the simulation.
+mod export_thread;
+
 /// Helper classes for measures and measure registries.
 mod measure;
 
+/// Catching panics in the sample and in the measurement function.
+mod panics;
+
+/// Statistics of the update algorithm reported by the samples.
+mod statistics;
+
+/// Random number generators owned by the simulation engine.
+mod rng;
+
+/// Periodic refreshing of the samples of the chains.
+mod refresh;
+
 /// The simulation orchestration engine is the core part of *ergothic*.
 mod simulation;
 
@@ -45,28 +79,129 @@ mod simulation;
 /// development and production modes.
 mod startup;
 
+/// Stopping criteria and the results returned once the simulation stops.
+mod stop;
+
+/// Sweeps over a grid of parameters of the sample.
+mod sweep;
+
+/// Parallel tempering, which simulates replicas of the sample at different
+/// couplings and swaps them between the couplings.
+mod tempering;
+
 
 // Following are the elements of the public API.
 
+/// Helpers for implementing `Sample::mutate` with the Metropolis algorithm.
+pub mod metropolis;
+
+/// Hybrid Monte-Carlo, which updates all of the degrees of freedom of a field
+/// at once by integrating its equations of motion.
+pub mod hmc;
+
+/// Cluster updates of spin models on the lattice, which don't suffer from the
+/// critical slowing down.
+pub mod cluster;
+
 /// Sample trait defines an object acting as a statistical sample.
 pub use simulation::Sample;
 
+/// A variant of the sample trait whose methods are handed the random number
+/// generator owned by the simulation engine.
+pub use simulation::SeededSample;
+
+/// A sample whose distribution depends on a coupling. Required for parallel
+/// tempering.
+pub use tempering::TemperedSample;
+
+/// A variant of the sample trait which is prepared with parameters given on the
+/// command line.
+pub use simulation::ParameterizedSample;
+
+/// Random number generator handed to `SeededSample` methods.
+pub use rng::Rng;
+
+/// Receives the statistics of the update algorithm reported by the samples.
+pub use statistics::UpdateStats;
+
 /// Positional index of a measure in the measure registry. Indices are wrapped
 /// in `MeasureIdx` type for type safety.
 pub use measure::MeasureIdx;
 
-/// Public interface to measure registry and the entry point function.
-pub struct Simulation {
+/// Positional indices of a set of measures registered with
+/// `Simulation::add_measure_set(..)`.
+pub use measure::MeasureSetIdx;
+
+/// A value recorded in one or more measures. Can be derived for structs with
+/// `#[derive(Measures)]` from the `ergothic_derive` crate.
+pub use measure::MeasureValue;
+
+#[doc(hidden)]
+pub use measure::nested_measure_name;
+
+/// Complex numbers, which can be recorded as measures.
+pub use num_complex::Complex;
+
+/// Values accumulated over the whole run, returned once the simulation stops.
+pub use stop::Results;
+
+/// Accumulated value of a single measure, part of `Results`.
+pub use stop::MeasureResult;
+
+/// The reason the simulation stopped.
+pub use stop::StopReason;
+
+/// Programmatic configuration of the simulation engine.
+pub use builder::SimulationBuilder;
+
+/// What happens to a chain whose sample or measurement function panicked.
+pub use panics::PanicPolicy;
+
+/// Container of the values accumulated by the simulation, which are exported
+/// on every flush.
+pub use measure::Measures;
+
+/// Interface to the data sinks the accumulated values are exported to.
+pub use export::Exporter;
+
+/// Exporter printing the accumulated values to stdout.
+pub use export::DebugExporter;
+
+/// Exporter sending the accumulated values to a MongoDB collection.
+pub use export::MongoExporter;
+
+/// Metadata of the exported data points, handed to the `on_start` hooks.
+pub use export::Metadata;
+
+/// Error returned by a failed export, handed to the `after_flush` hooks.
+pub use export::ExportError;
+
+/// Public interface to measure registry and the entry point function. The type
+/// parameter `S` is the type of the configuration samples, usually inferred
+/// from the measurement function passed to `run(..)`.
+pub struct Simulation<S> {
   name: String,
   measure_registry: measure::MeasureRegistry,
+  observables: Vec<measure::Observable<S>>,
+  checkpoint_codec: Option<checkpoint::Codec<S>>,
+  mutations_per_measurement: usize,
+  tracers: Vec<equilibrate::Tracer<S>>,
+  hooks: hooks::Hooks<S>,
+  config: Option<builder::SimulationBuilder>,
 }
 
-impl Simulation {
+impl<S: simulation::ParameterizedSample> Simulation<S> {
   /// Constructs a new simulation.
-  pub fn new<N: ToString>(name: N) -> Simulation {
+  pub fn new<N: ToString>(name: N) -> Simulation<S> {
     Simulation {
       name: name.to_string(),
       measure_registry: measure::MeasureRegistry::new(),
+      observables: Vec::new(),
+      checkpoint_codec: None,
+      mutations_per_measurement: 1,
+      tracers: Vec::new(),
+      hooks: hooks::Hooks::default(),
+      config: None,
     }
   }
 
@@ -76,11 +211,194 @@ impl Simulation {
     self.measure_registry.register(name.to_string())
   }
 
+  /// Registers a measure which is recorded on every `cadence`-th sample only.
+  /// Use it for observables which are much more expensive to compute than a
+  /// single mutation. The measurement function should check
+  /// `Measures::is_due(..)` before computing such an observable.
+  pub fn add_measure_every<N: ToString>(&mut self, name: N, cadence: usize)
+         -> MeasureIdx {
+    self.measure_registry.register_every(name.to_string(), cadence)
+  }
+
+  /// Registers all measures making up a `MeasureValue` of type `M`, usually a
+  /// struct deriving `Measures`. In the measurement function, fill the struct
+  /// and pass it to `Measures::accumulate_set(..)`.
+  pub fn add_measure_set<M: MeasureValue>(&mut self) -> MeasureSetIdx<M> {
+    self.measure_registry.register_set()
+  }
+
+  /// Registers a measure together with the function computing its value for a
+  /// given sample. The simulation engine evaluates the observable and
+  /// accumulates its value by itself, so no `MeasureIdx` has to be captured by
+  /// the measurement function. The evaluation time of every observable is
+  /// recorded in `__ergothic/observable_time/<name>`. A panicking observable
+  /// only loses its own value, unless a panic policy is set with `--on_panic`,
+  /// in which case the whole sample is handled by the policy. Returns the
+  /// index of the registered measure.
+  pub fn add_observable<N, F>(&mut self, name: N, f: F) -> MeasureIdx
+    where N: ToString, F: Fn(&S) -> f64 + Send + Sync + 'static {
+    self.add_observable_every(name, 1, f)
+  }
+
+  /// Same as `add_observable(..)`, but the observable is only evaluated on
+  /// every `cadence`-th sample.
+  pub fn add_observable_every<N, F>(&mut self, name: N, cadence: usize, f: F)
+         -> MeasureIdx
+    where N: ToString, F: Fn(&S) -> f64 + Send + Sync + 'static {
+    let name = name.to_string();
+    let idx = self.measure_registry.register_every(name.clone(), cadence);
+    let time_idx = self.measure_registry
+      .register_diagnostic(&format!("observable_time/{}", name));
+    self.observables.push(measure::Observable::new(idx, time_idx, name, f));
+    idx
+  }
+
+  /// Registers a function of the sample tracked by the adaptive
+  /// thermalization, enabled with `--adaptive_thermalization`. New samples are
+  /// mutated until all of the tracked values become stationary. If no tracers
+  /// are registered, the action of the sample is tracked.
+  pub fn add_thermalization_tracer<F>(&mut self, f: F)
+    where F: Fn(&S) -> f64 + Send + Sync + 'static {
+    self.tracers.push(::std::sync::Arc::new(f));
+  }
+
+  /// Sets the number of mutations applied to the sample between subsequent
+  /// measurements. Larger values reduce the autocorrelation of the measured
+  /// values. The default is 1. Can be overridden on the command line with
+  /// `--mutations_per_measurement`.
+  pub fn set_mutations_per_measurement(&mut self, mutations: usize) {
+    assert!(mutations > 0,
+            "At least one mutation per measurement is required.");
+    self.mutations_per_measurement = mutations;
+  }
+
+  /// Registers a hook called with the metadata of the run when the simulation
+  /// starts, before any sample is prepared. In a parameter sweep, it is
+  /// called at the start of every run.
+  pub fn on_start<F>(&mut self, f: F)
+    where F: FnMut(&Metadata) + 'static {
+    self.hooks.on_start.push(Box::new(f));
+  }
+
+  /// Registers a hook called on every sample once it has been thermalized,
+  /// including samples thermalized again on refresh, but not samples restored
+  /// from a checkpoint. Called in the thread running the chain.
+  pub fn after_thermalize<F>(&mut self, f: F)
+    where F: Fn(&mut S) + Send + Sync + 'static {
+    self.hooks.after_thermalize.push(::std::sync::Arc::new(f));
+  }
+
+  /// Registers a hook called with the accumulated values on every flush,
+  /// before they are handed over to the export thread.
+  pub fn before_flush<F>(&mut self, f: F)
+    where F: FnMut(&measure::Measures) + 'static {
+    self.hooks.before_flush.push(Box::new(f));
+  }
+
+  /// Registers a hook called with the result of every attempt to export the
+  /// accumulated values. Exports run in a background thread, so the result is
+  /// seen on the next flush. Failed exports are retried on the next flush.
+  pub fn after_flush<F>(&mut self, f: F)
+    where F: FnMut(&Result<(), ExportError>) + 'static {
+    self.hooks.after_flush.push(Box::new(f));
+  }
+
+  /// Registers a hook called with the results once the simulation stops and
+  /// the remaining values have been flushed. Not called if the simulation
+  /// panics.
+  pub fn on_stop<F>(&mut self, f: F)
+    where F: FnMut(&Results) + 'static {
+    self.hooks.on_stop.push(Box::new(f));
+  }
+
+  /// Configures the simulation engine with the `builder` instead of parsing
+  /// the command line. Checkpoints and parameter sweeps are not available in
+  /// this mode, and the process is never exited by the engine.
+  pub fn configure(&mut self, builder: SimulationBuilder) {
+    self.config = Some(builder);
+  }
+
+  /// Enables checkpoints for serializable samples. With `--checkpoint`, the
+  /// samples and the values which haven't been exported yet are periodically
+  /// written to a local file, and `--resume` picks them up after a restart
+  /// without thermalizing new samples. Samples which own their random number
+  /// generators should serialize their states as well.
+  pub fn enable_checkpoints(&mut self)
+    where S: serde::Serialize + serde::de::DeserializeOwned {
+    self.checkpoint_codec = Some(checkpoint::Codec::new());
+  }
+
   /// Entry point function. All ergothic simulations should call this function.
-  /// Consumes `self` to indicate that the simulation runs in an infinite loop
-  /// and never returns.
-  pub fn run<S: simulation::Sample, F>(self, f: F)
-    where F: Fn(&S, &mut measure::Measures) {
-    startup::run_simulation(&self.name, self.measure_registry, f);
+  /// Unless stop criteria are given on the command line or with
+  /// `configure(..)`, the simulation runs in an infinite loop and never
+  /// returns. Otherwise, returns the values accumulated over the whole run
+  /// once the simulation stops. Observables
+  /// registered with `add_observable(..)` are evaluated before calling `f`.
+  pub fn run<F>(self, f: F) -> Results
+    where F: Fn(&S, &mut measure::Measures) + Sync {
+    self.run_with_context(|| (), |s, _, ms| f(s, ms))
+  }
+
+  /// Same as `run(..)`, but every chain owns a context value created with
+  /// `init` before the chain starts, which is handed mutably to `f` along with
+  /// each sample. Use it for scratch state of the measurement code, such as
+  /// buffers, FFT plans or caches. Contexts are created in the threads running
+  /// the chains, so they don't have to be `Send`.
+  pub fn run_with_context<C, I, F>(self, init: I, f: F) -> Results
+    where I: Fn() -> C + Sync,
+          F: Fn(&S, &mut C, &mut measure::Measures) + Sync {
+    let (init, f, observables) = (&init, &f, &self.observables);
+    // Panics in the observables are isolated, unless a panic policy is set.
+    let new_measure_fn = move |isolate: bool| move || {
+      let mut context = init();
+      move |s: &S, ms: &mut measure::Measures| {
+        for observable in observables.iter() {
+          observable.measure(s, ms, isolate);
+        }
+        f(s, &mut context, ms);
+      }
+    };
+    let (codec, tracers, mut hooks) =
+      (self.checkpoint_codec, self.tracers, self.hooks);
+    startup::start(&self.name, self.measure_registry,
+                   self.mutations_per_measurement, self.config,
+                   |parameters| {
+                     let isolate = parameters.on_panic.is_none();
+                     ::simulation::run(parameters, codec, tracers.clone(),
+                                       &mut hooks, new_measure_fn(isolate))
+                   })
+  }
+
+  /// Entry point function for parallel tempering. Instead of a single sample,
+  /// each chain simulates a replica of the sample at each of the `couplings`,
+  /// and neighbouring replicas periodically swap their configurations. List
+  /// the couplings in ascending or descending order, since swaps are only
+  /// attempted between neighbours. Values measured at different couplings are
+  /// exported separately: the measure `name` recorded at the coupling `c` is
+  /// exported as `name@c`. Otherwise equivalent to `run(..)`.
+  pub fn run_tempering<F>(self, couplings: Vec<f64>, f: F) -> Results
+    where S: TemperedSample, F: Fn(&S, &mut measure::Measures) + Sync {
+    let (f, observables) = (&f, &self.observables);
+    let new_measure_fn = move || {
+      move |s: &S, ms: &mut measure::Measures| {
+        for observable in observables.iter() {
+          observable.measure(s, ms, true);
+        }
+        f(s, ms);
+      }
+    };
+    let (tracers, mut hooks) = (self.tracers, self.hooks);
+    startup::start(&self.name, self.measure_registry,
+                   self.mutations_per_measurement, self.config,
+                   |parameters| ::simulation::run_tempering(
+                     parameters, couplings.clone(), tracers.clone(),
+                     &mut hooks, new_measure_fn))
+  }
+
+  /// Entry point function for simulations whose measures are all registered
+  /// with `add_observable(..)`. Equivalent to `run(..)` with an empty
+  /// measurement function.
+  pub fn run_observables(self) -> Results {
+    self.run(|_, _| {})
   }
 }
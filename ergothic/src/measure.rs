@@ -1,5 +1,9 @@
 use ::accumulate::Acc;
+use ::panics::panic_message;
+use ::simulation::secs;
 use ::std::collections::HashMap;
+use ::std::marker::PhantomData;
+use ::std::time::Instant;
 
 /// Represents a physical observable. Measuring expectation values of
 /// observables is the purpose of any *ergothic* simulation.
@@ -13,6 +17,60 @@ pub struct Measure {
   /// The corresponding accumulator. Consumes values of the observable, measured
   /// for configuration samples drawn from the ergodic ensemble.
   pub acc: Acc,
+
+  /// The measure is recorded on every `cadence`-th sample only. Expensive
+  /// observables can be given a large cadence so that they don't dominate the
+  /// wall time of the simulation.
+  #[serde(default = "default_cadence", serialize_with = "serialize_as_i64",
+          deserialize_with = "deserialize_from_i64")]
+  pub cadence: usize,
+
+  /// Version of the collection of measures in which the measure first
+  /// appeared. Measures registered before the simulation starts have version
+  /// 0, see `Measures::find_or_register(..)`.
+  #[serde(default, serialize_with = "serialize_as_i64",
+          deserialize_with = "deserialize_from_i64")]
+  pub since_version: usize,
+}
+
+fn default_cadence() -> usize {
+  1
+}
+
+/// Serializes an unsigned field as a signed 64-bit integer, which is the only
+/// integer type BSON can store without loss. Keeps the exported measures
+/// independent of how the `bson` crate treats unsigned integers.
+fn serialize_as_i64<S: ::serde::Serializer>(value: &usize, serializer: S)
+   -> Result<S::Ok, S::Error> {
+  use ::serde::ser::Error;
+  if *value as u64 > i64::MAX as u64 {
+    return Err(S::Error::custom(format!("{} doesn't fit into i64.", value)));
+  }
+  serializer.serialize_i64(*value as i64)
+}
+
+/// Deserializes an unsigned field written by `serialize_as_i64(..)`.
+fn deserialize_from_i64<'de, D: ::serde::Deserializer<'de>>(deserializer: D)
+   -> Result<usize, D::Error> {
+  use ::serde::de::Error;
+  let value = <i64 as ::serde::Deserialize>::deserialize(deserializer)?;
+  if value < 0 {
+    return Err(D::Error::custom(format!("Expected a non-negative integer, \
+                                         found {}.", value)));
+  }
+  Ok(value as usize)
+}
+
+/// Names of the diagnostic measures recorded by the simulation engine itself
+/// start with this prefix. User-defined measures can't use it.
+pub const DIAGNOSTIC_PREFIX: &str = "__ergothic/";
+
+impl Measure {
+  /// Returns true if the measure is a diagnostic recorded by the simulation
+  /// engine rather than a physical observable.
+  pub fn is_diagnostic(&self) -> bool {
+    self.name.starts_with(DIAGNOSTIC_PREFIX)
+  }
 }
 
 /// A thin wrapper around a positional index corresponding to a specific
@@ -22,12 +80,203 @@ pub struct Measure {
 #[derive(Clone, Copy)]
 pub struct MeasureIdx(usize);
 
+/// A value which is recorded in one or more measures. Implemented for scalars,
+/// complex numbers and arrays. Structs declaring whole sets of measures can
+/// implement it with `#[derive(Measures)]` from the `ergothic_derive` crate.
+pub trait MeasureValue {
+  /// Appends the names of the measures making up the value to `names`. The
+  /// `name` argument is the name given to the value as a whole.
+  fn measure_names(name: &str, names: &mut Vec<String>);
+
+  /// Feeds the components of the value to `f`, in the same order as the names
+  /// produced by `measure_names(..)`.
+  fn measure_values(&self, f: &mut dyn FnMut(f64));
+}
+
+impl MeasureValue for f64 {
+  fn measure_names(name: &str, names: &mut Vec<String>) {
+    names.push(name.to_string());
+  }
+
+  fn measure_values(&self, f: &mut dyn FnMut(f64)) {
+    f(*self);
+  }
+}
+
+impl MeasureValue for f32 {
+  fn measure_names(name: &str, names: &mut Vec<String>) {
+    names.push(name.to_string());
+  }
+
+  fn measure_values(&self, f: &mut dyn FnMut(f64)) {
+    f(*self as f64);
+  }
+}
+
+/// Complex values are recorded as two measures, one for the real and one for
+/// the imaginary part.
+impl<T: MeasureValue> MeasureValue for ::num_complex::Complex<T> {
+  fn measure_names(name: &str, names: &mut Vec<String>) {
+    T::measure_names(&format!("Re {}", name), names);
+    T::measure_names(&format!("Im {}", name), names);
+  }
+
+  fn measure_values(&self, f: &mut dyn FnMut(f64)) {
+    self.re.measure_values(f);
+    self.im.measure_values(f);
+  }
+}
+
+/// Arrays are recorded element-wise. The measure corresponding to the `i`-th
+/// element is called `name[i]`, unless the name contains a `{}` placeholder,
+/// which is then replaced with `i`. For instance, an array named `G({})`
+/// gives the measures `G(0)`, `G(1)` and so on.
+impl<T: MeasureValue, const N: usize> MeasureValue for [T; N] {
+  fn measure_names(name: &str, names: &mut Vec<String>) {
+    for i in 0..N {
+      let element = if name.contains("{}") {
+        name.replacen("{}", &i.to_string(), 1)
+      } else {
+        format!("{}[{}]", name, i)
+      };
+      T::measure_names(&element, names);
+    }
+  }
+
+  fn measure_values(&self, f: &mut dyn FnMut(f64)) {
+    for value in self.iter() {
+      value.measure_values(f);
+    }
+  }
+}
+
+/// Gives the name of a measure nested within a value called `name`. Used by
+/// the code generated with `#[derive(Measures)]`.
+#[doc(hidden)]
+pub fn nested_measure_name(name: &str, field: &str) -> String {
+  if name.is_empty() {
+    field.to_string()
+  } else {
+    format!("{}.{}", name, field)
+  }
+}
+
+/// Positional indices of all measures making up a `MeasureValue` of type `M`.
+/// Returned by `Simulation::add_measure_set(..)`.
+pub struct MeasureSetIdx<M> {
+  idxs: Vec<MeasureIdx>,
+  _value: PhantomData<fn(&M)>,
+}
+
+/// A measure paired with the function computing its value for a sample of
+/// type `S`. Registered with `Simulation::add_observable(..)`. The time spent
+/// evaluating the observable is recorded in the diagnostic measure
+/// `__ergothic/observable_time/<name>`.
+pub struct Observable<S> {
+  idx: MeasureIdx,
+  time_idx: MeasureIdx,
+  name: String,
+  f: Box<dyn Fn(&S) -> f64 + Send + Sync>,
+}
+
+impl<S> Observable<S> {
+  /// Constructs an observable recording the values of `f` in the measure
+  /// `name` pointed to by `idx`, and its evaluation time in the diagnostic
+  /// measure pointed to by `time_idx`.
+  pub fn new<F>(idx: MeasureIdx, time_idx: MeasureIdx, name: String, f: F)
+         -> Observable<S>
+    where F: Fn(&S) -> f64 + Send + Sync + 'static {
+    Observable {
+      idx,
+      time_idx,
+      name,
+      f: Box::new(f),
+    }
+  }
+
+  /// Evaluates the observable for the sample `s` and accumulates its value,
+  /// unless the measure is not due on the current sample. If `isolate` is
+  /// true, a panic in the observable is logged and its value is skipped,
+  /// while the sample and other observables are unaffected. Otherwise, the
+  /// panic propagates to the panic policy of the simulation.
+  pub fn measure(&self, s: &S, measures: &mut Measures, isolate: bool) {
+    if !measures.is_due(self.idx) {
+      return;
+    }
+    let start = Instant::now();
+    let value = if isolate {
+      match ::std::panic::catch_unwind(
+              ::std::panic::AssertUnwindSafe(|| (self.f)(s))) {
+        Ok(value) => Some(value),
+        Err(payload) => {
+          error!("Observable '{}' panicked: {}", self.name,
+                 panic_message(&*payload));
+          None
+        },
+      }
+    } else {
+      Some((self.f)(s))
+    };
+    measures.accumulate(self.time_idx, secs(start.elapsed()));
+    if let Some(value) = value {
+      measures.accumulate(self.idx, value);
+    }
+  }
+}
+
+/// Accumulated values of a collection of measures, saved so that the values
+/// recorded afterwards can be discarded.
+pub struct SavedValues(Vec<Acc>);
+
 /// A collection of physical observables. Determining expectation values of each
 /// of the measures with reasonable accuracy is the sole purpose of the
 /// *ergothic* simulation.
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize)]
 pub struct Measures {
   measures: Vec<Measure>,
+
+  /// Incremented every time a new measure is registered while the simulation
+  /// is running. Data points exported with different versions contain
+  /// different sets of measures.
+  #[serde(default, serialize_with = "serialize_as_i64",
+          deserialize_with = "deserialize_from_i64")]
+  version: usize,
+
+  /// Index for looking up measures by name. Kept in sync with the list of
+  /// measures, and rebuilt after deserialization.
+  #[serde(skip)]
+  name_index: HashMap<String, MeasureIdx>,
+
+  /// Index of the sample currently being measured. Used to decide which
+  /// measures are due according to their cadence.
+  #[serde(skip)]
+  step: usize,
+}
+
+/// The serialized form of `Measures`, which doesn't include the name index.
+#[derive(Deserialize)]
+struct SerializedMeasures {
+  measures: Vec<Measure>,
+
+  #[serde(default, deserialize_with = "deserialize_from_i64")]
+  version: usize,
+}
+
+/// Rebuilds the name index of the deserialized measures.
+impl<'de> ::serde::Deserialize<'de> for Measures {
+  fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D)
+     -> Result<Measures, D::Error> {
+    let serialized = SerializedMeasures::deserialize(deserializer)?;
+    let name_index = serialized.measures.iter().enumerate()
+      .map(|(i, measure)| (measure.name.clone(), MeasureIdx(i)))
+      .collect();
+    Ok(Measures {
+      measures: serialized.measures,
+      version: serialized.version,
+      name_index,
+      step: 0,
+    })
+  }
 }
 
 impl Measures {
@@ -35,6 +284,9 @@ impl Measures {
   pub fn new_empty() -> Measures {
     Measures {
       measures: Vec::new(),
+      version: 0,
+      name_index: HashMap::new(),
+      step: 0,
     }
   }
 
@@ -43,11 +295,113 @@ impl Measures {
     &self.measures
   }
 
+  /// Gives the version of the collection of measures. The version is 0 unless
+  /// new measures have been registered while the simulation was running.
+  pub fn version(&self) -> usize {
+    self.version
+  }
+
+  /// Lookup of the measure by its name. Returns a measure index or `None` if a
+  /// measure with a given name doesn't exist.
+  pub fn find(&self, name: &str) -> Option<MeasureIdx> {
+    self.name_index.get(name).cloned()
+  }
+
+  /// Returns the index of the measure with a given `name`, registering it if
+  /// it doesn't exist yet. Unlike `Simulation::add_measure(..)`, this is safe
+  /// to call from the measurement function while the simulation is running.
+  /// Each registration of a new measure increments the version of the
+  /// collection, which is recorded in every exported data point, so that
+  /// aggregating old and new data points stays consistent.
+  pub fn find_or_register(&mut self, name: &str) -> MeasureIdx {
+    self.find_or_register_every(name, 1)
+  }
+
+  /// Same as `find_or_register(..)`, but a newly registered measure is only
+  /// recorded on every `cadence`-th sample. Panics if `cadence` is zero.
+  pub fn find_or_register_every(&mut self, name: &str, cadence: usize)
+         -> MeasureIdx {
+    if let Some(idx) = self.find(name) {
+      return idx;
+    }
+    check_measure_name(name);
+    if cadence == 0 {
+      panic!("Measure '{}' was given a zero cadence.", name);
+    }
+    self.version += 1;
+    info!("Registered measure '{}', measures version is now {}.",
+          name, self.version);
+    self.push(Measure {
+      name: name.to_string(),
+      acc: Acc::new(),
+      cadence,
+      since_version: self.version,
+    })
+  }
+
+  /// Returns the index of the diagnostic measure `__ergothic/<name>`,
+  /// registering it if it doesn't exist yet. Diagnostic measures are
+  /// maintained by the simulation engine and are the same on every node
+  /// running the same binary, so registering them doesn't change the version
+  /// of the collection.
+  pub fn find_or_register_diagnostic(&mut self, name: &str) -> MeasureIdx {
+    let name = format!("{}{}", DIAGNOSTIC_PREFIX, name);
+    if let Some(idx) = self.find(&name) {
+      return idx;
+    }
+    let since_version = self.version;
+    self.push(Measure {
+      name,
+      acc: Acc::new(),
+      cadence: 1,
+      since_version,
+    })
+  }
+
+  /// Merges the accumulated values of `other` into this collection. Measures
+  /// are matched by name, so the two collections don't need to have the same
+  /// layout. Measures missing from this collection are added to it.
+  pub fn merge(&mut self, other: &Measures) {
+    self.merge_suffixed(other, "");
+  }
+
+  /// Same as `merge(..)`, but the name of every measure of `other` is given
+  /// the `suffix`. Used to keep the values measured in different settings,
+  /// such as different couplings, apart.
+  pub fn merge_suffixed(&mut self, other: &Measures, suffix: &str) {
+    for measure in other.measures.iter() {
+      let name = format!("{}{}", measure.name, suffix);
+      match self.find(&name) {
+        Some(idx) => self.accumulator(idx).merge(measure.acc.clone()),
+        None => {
+          self.push(Measure {
+            name,
+            ..measure.clone()
+          });
+        },
+      }
+    }
+    self.version = ::std::cmp::max(self.version, other.version);
+  }
+
+  /// Appends a measure to the collection and returns its index.
+  fn push(&mut self, measure: Measure) -> MeasureIdx {
+    let idx = MeasureIdx(self.measures.len());
+    self.name_index.insert(measure.name.clone(), idx);
+    self.measures.push(measure);
+    idx
+  }
+
   /// Returns an immutable reference to the measure pointed to by `idx`.
   pub fn get(&self, idx: MeasureIdx) -> &Measure {
     &self.measures[idx.0]
   }
 
+  /// Returns true if none of the measures have recorded any samples.
+  pub fn is_empty(&self) -> bool {
+    self.measures.iter().all(|m| m.acc.num_of_samples() == 0.0)
+  }
+
   /// Resets accumulators for all measures, effectively forgetting about all
   /// recorded samples.
   pub fn reset(&mut self) {
@@ -56,21 +410,72 @@ impl Measures {
     }
   }
 
+  /// Saves the accumulated values, so that the values recorded afterwards can
+  /// be discarded with `restore(..)`.
+  pub fn save(&self) -> SavedValues {
+    SavedValues(self.measures.iter().map(|m| m.acc.clone()).collect())
+  }
+
+  /// Restores the values saved with `save()`, discarding the ones recorded
+  /// since. Measures registered since are reset.
+  pub fn restore(&mut self, saved: SavedValues) {
+    let mut saved = saved.0.into_iter();
+    for measure in self.measures.iter_mut() {
+      measure.acc = saved.next().unwrap_or_else(Acc::new);
+    }
+  }
+
   /// Returns a mutable reference to the accumulator corresponding to the
   /// measure pointed to by `idx`.
   pub fn accumulator(&mut self, idx: MeasureIdx) -> &mut Acc {
     &mut self.measures[idx.0].acc
   }
 
-  /// Shorthand for `self.accumulator(idx).consume(value)`.
+  /// Shorthand for `self.accumulator(idx).consume(value)`. Values supplied
+  /// for measures which are not due on the current sample (see `is_due(..)`)
+  /// are ignored, so that every measure is recorded with its own cadence.
   pub fn accumulate(&mut self, idx: MeasureIdx, value: f64) {
-    self.accumulator(idx).consume(value);
+    if self.is_due(idx) {
+      self.accumulator(idx).consume(value);
+    }
+  }
+
+  /// Accumulates all components of `value` in the measures pointed to by
+  /// `set`. Filling a struct deriving `Measures` and passing it here is enough
+  /// to record every measure declared by the struct.
+  pub fn accumulate_set<M: MeasureValue>(&mut self, set: &MeasureSetIdx<M>,
+                                         value: &M) {
+    let mut idxs = set.idxs.iter();
+    value.measure_values(&mut |v| {
+      let idx = *idxs.next().expect("MeasureValue produced too many values.");
+      self.accumulate(idx, v);
+    });
+  }
+
+  /// Returns true if the measure pointed to by `idx` should be recorded for the
+  /// current sample. Measurement code should check this before computing
+  /// expensive observables.
+  pub fn is_due(&self, idx: MeasureIdx) -> bool {
+    self.step % self.measures[idx.0].cadence == 0
+  }
+
+  /// Returns true if at least one of the measures should be recorded for the
+  /// current sample. The simulation engine skips the measurement function
+  /// entirely when nothing is due.
+  pub fn any_due(&self) -> bool {
+    self.measures.iter()
+      .any(|m| !m.is_diagnostic() && self.step % m.cadence == 0)
+  }
+
+  /// Advances the sample counter used for deciding which measures are due.
+  /// Called by the simulation engine after measuring each sample.
+  pub fn next_step(&mut self) {
+    self.step = self.step.wrapping_add(1);
   }
 }
 
 pub struct MeasureRegistry {
   measures: Measures,
-  name_index: HashMap<String, MeasureIdx>,
 }
 
 /// Contains a list of measures and a map from measure names to measure indexes.
@@ -79,19 +484,13 @@ impl MeasureRegistry {
   pub fn new() -> MeasureRegistry {
     MeasureRegistry {
       measures: Measures::new_empty(),
-      name_index: HashMap::new(),
     }
   }
   
-  /// Returns an immutable reference to the collection of measures.
-  pub fn measures(&self) -> &Measures {
-    &self.measures
-  }
-
   /// Lookup of the measure by its name. Returns a measure index or `None` if a
   /// measure with a given name doesn't exist.
-  pub fn find(&mut self, name: &str) -> Option<MeasureIdx> {
-    self.name_index.get(name).cloned()
+  pub fn find(&self, name: &str) -> Option<MeasureIdx> {
+    self.measures.find(name)
   }
 
   /// Returns an interior-immutable list of measures suitable for using in the
@@ -106,21 +505,95 @@ impl MeasureRegistry {
   /// index of the measure in the collection of measures. If a measure with the
   /// same name has been registered before, panics.
   pub fn register(&mut self, name: String) -> MeasureIdx {
-    if self.name_index.contains_key(&name) {
+    self.register_every(name, 1)
+  }
+
+  /// Same as `register(..)`, but the measure is only recorded on every
+  /// `cadence`-th sample. Panics if `cadence` is zero.
+  pub fn register_every(&mut self, name: String, cadence: usize)
+         -> MeasureIdx {
+    check_measure_name(&name);
+    if cadence == 0 {
+      panic!("Measure '{}' was given a zero cadence.", &name);
+    }
+    if self.find(&name).is_some() {
       panic!("Ambiguous measure definition: '{}' was registered twice.", &name);
     }
-    self.measures.measures.push(Measure {
-      name: name.clone(),
+    self.measures.push(Measure {
+      name,
       acc: Acc::new(),
-    });
-    let res_idx = MeasureIdx(self.measures.measures.len() - 1);
-    self.name_index.insert(name, res_idx);
-    res_idx
+      cadence,
+      since_version: 0,
+    })
   }
 
-  /// Returns a mutable reference to the accumulator corresponding to a measure
-  /// pointed to by `idx`.
-  pub fn accumulator(&mut self, idx: MeasureIdx) -> &mut Acc {
-    self.measures.accumulator(idx)
+  /// Registers the diagnostic measure `__ergothic/<name>`, unless it exists
+  /// already. See `Measures::find_or_register_diagnostic(..)`.
+  pub fn register_diagnostic(&mut self, name: &str) -> MeasureIdx {
+    self.measures.find_or_register_diagnostic(name)
+  }
+
+  /// Registers every measure making up a `MeasureValue` of type `M`. Panics if
+  /// any of the names has been registered before.
+  pub fn register_set<M: MeasureValue>(&mut self) -> MeasureSetIdx<M> {
+    let mut names = Vec::new();
+    M::measure_names("", &mut names);
+    MeasureSetIdx {
+      idxs: names.into_iter().map(|name| self.register(name)).collect(),
+      _value: PhantomData,
+    }
+  }
+}
+
+/// Panics if `name` can't be used for a user-defined measure.
+fn check_measure_name(name: &str) {
+  if name.starts_with(DIAGNOSTIC_PREFIX) {
+    panic!("Measure name '{}' uses the reserved prefix '{}'.",
+           name, DIAGNOSTIC_PREFIX);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::MeasureRegistry;
+  use super::Measures;
+  use super::Observable;
+
+  /// Measures of an observable evaluating `f`, along with the observable.
+  fn observable(f: fn(&f64) -> f64) -> (Measures, Observable<f64>) {
+    let mut registry = MeasureRegistry::new();
+    let idx = registry.register("x".to_string());
+    let time_idx = registry.register_diagnostic("observable_time/x");
+    (registry.freeze(), Observable::new(idx, time_idx, "x".to_string(), f))
+  }
+
+  fn samples(measures: &Measures, name: &str) -> f64 {
+    let idx = measures.find(name).expect("Missing measure.");
+    measures.get(idx).acc.num_of_samples()
+  }
+
+  #[test]
+  fn observable_is_timed() {
+    let (mut measures, observable) = observable(|s| 2.0 * s);
+    observable.measure(&1.0, &mut measures, true);
+    observable.measure(&2.0, &mut measures, false);
+    let idx = measures.find("x").unwrap();
+    assert_eq!(measures.get(idx).acc.value(), 3.0);
+    assert_eq!(samples(&measures, "__ergothic/observable_time/x"), 2.0);
+  }
+
+  #[test]
+  fn isolated_panic_skips_the_value() {
+    let (mut measures, observable) = observable(|_| panic!("Broken."));
+    observable.measure(&1.0, &mut measures, true);
+    assert_eq!(samples(&measures, "x"), 0.0);
+    assert_eq!(samples(&measures, "__ergothic/observable_time/x"), 1.0);
+  }
+
+  #[test]
+  #[should_panic(expected = "Broken.")]
+  fn panic_propagates_unless_isolated() {
+    let (mut measures, observable) = observable(|_| panic!("Broken."));
+    observable.measure(&1.0, &mut measures, false);
   }
 }
@@ -0,0 +1,512 @@
+//! A configuration made up of sites, such as a lattice field, implements
+//! `Sites`. The `Metropolis` helper then updates it one site at a time,
+//! drawing new values from a symmetric `Proposal` and accepting or rejecting
+//! them according to the change of the action. The acceptance rate is
+//! recorded automatically once the sample reports it in
+//! `Sample::report_statistics`. Optionally, the width of the proposal is
+//! tuned toward a target acceptance rate during the thermalization:
+//!
+//! ```no_run
+//! # extern crate ergothic;
+//! use ergothic::metropolis::{Metropolis, Sites, UniformShift};
+//!
+//! struct Chain {
+//!   x: Vec<f64>,
+//! }
+//!
+//! impl Sites for Chain {
+//!   type Value = f64;
+//!
+//!   fn num_sites(&self) -> usize {
+//!     self.x.len()
+//!   }
+//!
+//!   fn site(&self, site: usize) -> f64 {
+//!     self.x[site]
+//!   }
+//!
+//!   fn set_site(&mut self, site: usize, value: f64) {
+//!     self.x[site] = value;
+//!   }
+//!
+//!   fn local_action(&self, site: usize) -> f64 {
+//!     let next = self.x[(site + 1) % self.x.len()];
+//!     let prev = self.x[(site + self.x.len() - 1) % self.x.len()];
+//!     ((self.x[site] - next).powi(2) + (self.x[site] - prev).powi(2)) / 2.0
+//!   }
+//! }
+//!
+//! struct MySample {
+//!   chain: Chain,
+//!   metropolis: Metropolis<UniformShift>,
+//! }
+//!
+//! impl ergothic::SeededSample for MySample {
+//!   fn prepare(_rng: &mut ergothic::Rng) -> MySample {
+//!     MySample {
+//!       chain: Chain { x: vec![0.0; 100] },
+//!       metropolis: Metropolis::new(UniformShift { width: 1.0 })
+//!                     .with_tuning(0.5),
+//!     }
+//!   }
+//!
+//!   fn mutate(&mut self, rng: &mut ergothic::Rng) {
+//!     self.metropolis.sweep(&mut self.chain, rng);
+//!   }
+//!
+//!   fn report_statistics(&mut self, stats: &mut ergothic::UpdateStats) {
+//!     self.metropolis.report("acceptance", stats);
+//!   }
+//! }
+//! # fn main() {}
+//! ```
+
+use ::rand::Rng as RandRng;
+use ::rand::distributions::StandardNormal;
+use ::rng::Rng;
+use ::statistics::UpdateStats;
+
+/// Decides on a Metropolis step changing the action by `delta_s`. Steps
+/// decreasing the action are always accepted, and steps increasing it are
+/// accepted with the probability `exp(-delta_s)`. Returns true if the step is
+/// accepted.
+pub fn accept(delta_s: f64, rng: &mut Rng) -> bool {
+  delta_s <= 0.0 || rng.gen::<f64>() < (-delta_s).exp()
+}
+
+/// Counts the proposed and the accepted Metropolis steps.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct Acceptance {
+  proposed: u64,
+  accepted: u64,
+}
+
+impl Acceptance {
+  /// Constructs a counter with no steps recorded.
+  pub fn new() -> Acceptance {
+    Acceptance::default()
+  }
+
+  /// Records a proposed step, which was either `accepted` or rejected.
+  pub fn record(&mut self, accepted: bool) {
+    self.proposed += 1;
+    if accepted {
+      self.accepted += 1;
+    }
+  }
+
+  /// Number of the proposed steps.
+  pub fn proposed(&self) -> u64 {
+    self.proposed
+  }
+
+  /// Number of the accepted steps.
+  pub fn accepted(&self) -> u64 {
+    self.accepted
+  }
+
+  /// Fraction of the proposed steps which were accepted, if any were
+  /// proposed.
+  pub fn rate(&self) -> Option<f64> {
+    if self.proposed == 0 {
+      None
+    } else {
+      Some(self.accepted as f64 / self.proposed as f64)
+    }
+  }
+
+  /// Records the acceptance rate in the diagnostic measure
+  /// `__ergothic/<name>` and resets the counter. Nothing is recorded if no
+  /// steps were proposed since the last reset.
+  pub fn report(&mut self, name: &str, stats: &mut UpdateStats) {
+    if let Some(rate) = self.rate() {
+      stats.record(name, rate);
+    }
+    *self = Acceptance::new();
+  }
+}
+
+/// Proposes a new value for a site given its `current` value. The proposal
+/// must be symmetric: proposing `b` from `a` must be as likely as proposing
+/// `a` from `b`. Otherwise, the Metropolis steps violate detailed balance.
+pub trait Proposal<T> {
+  /// Draws the proposed value.
+  fn propose(&self, current: &T, rng: &mut Rng) -> T;
+
+  /// Gives the width of the proposal, if it can be tuned with
+  /// `Metropolis::with_tuning(..)`. The default implementation gives `None`.
+  fn width(&self) -> Option<f64> {
+    None
+  }
+
+  /// Sets the width of the proposal. Only called if `width()` gives a value.
+  fn set_width(&mut self, _width: f64) {}
+}
+
+/// Shifts the value by a random number uniformly distributed within
+/// `[-width, width]`.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct UniformShift {
+  /// Maximum shift of the value.
+  pub width: f64,
+}
+
+impl Proposal<f64> for UniformShift {
+  fn propose(&self, current: &f64, rng: &mut Rng) -> f64 {
+    current + self.width * (2.0 * rng.gen::<f64>() - 1.0)
+  }
+
+  fn width(&self) -> Option<f64> {
+    Some(self.width)
+  }
+
+  fn set_width(&mut self, width: f64) {
+    self.width = width;
+  }
+}
+
+/// Shifts the value by a normally distributed random number with zero mean
+/// and the standard deviation `sigma`.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct GaussianShift {
+  /// Standard deviation of the shift.
+  pub sigma: f64,
+}
+
+impl Proposal<f64> for GaussianShift {
+  fn propose(&self, current: &f64, rng: &mut Rng) -> f64 {
+    current + self.sigma * rng.sample(StandardNormal)
+  }
+
+  fn width(&self) -> Option<f64> {
+    Some(self.sigma)
+  }
+
+  fn set_width(&mut self, sigma: f64) {
+    self.sigma = sigma;
+  }
+}
+
+/// A configuration made up of sites which can be updated one at a time, such
+/// as a field on the lattice.
+pub trait Sites {
+  /// Value of the degrees of freedom living on a single site.
+  type Value: Clone;
+
+  /// Number of sites. Sites are indexed from 0 to `num_sites() - 1`.
+  fn num_sites(&self) -> usize;
+
+  /// Gives the value on the `site`.
+  fn site(&self, site: usize) -> Self::Value;
+
+  /// Sets the value on the `site`.
+  fn set_site(&mut self, site: usize, value: Self::Value);
+
+  /// Gives the part of the action which depends on the value on the `site`.
+  /// Only differences of the local action are used, so the terms not
+  /// involving the site may be left out.
+  fn local_action(&self, site: usize) -> f64;
+}
+
+/// The width of the proposal is adjusted after every `TUNING_BATCH` steps
+/// proposed while tuning.
+const TUNING_BATCH: u64 = 100;
+
+/// Adjusts the width of the proposal toward a target acceptance rate while
+/// the sample is being thermalized.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Tuning {
+  /// Acceptance rate the width is tuned for.
+  target: f64,
+
+  /// Steps proposed since the last adjustment.
+  batch: Acceptance,
+
+  /// Width of the proposal after the last adjustment, once known.
+  width: Option<f64>,
+
+  /// Set once the thermalization is over. The width is no longer adjusted.
+  frozen: bool,
+}
+
+impl Tuning {
+  /// Records a step, and adjusts the width of the `proposal` once the batch
+  /// is complete. Widths giving too many rejections are narrowed, and widths
+  /// giving too many acceptances are widened.
+  fn record<T, P: Proposal<T>>(&mut self, accepted: bool, proposal: &mut P) {
+    let width = proposal.width().expect(
+      "Metropolis::with_tuning(..) requires a proposal with a tunable width.");
+    self.width = Some(width);
+    self.batch.record(accepted);
+    if self.batch.proposed() < TUNING_BATCH {
+      return;
+    }
+    let rate = self.batch.rate().unwrap();
+    let width = width * (rate - self.target).exp();
+    proposal.set_width(width);
+    self.width = Some(width);
+    self.batch = Acceptance::new();
+  }
+}
+
+/// Local Metropolis updates of `Sites` with the proposal `P`. Keeps track of
+/// the acceptance rate. Store it in the sample next to the configuration, so
+/// that the two can be borrowed at the same time.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Metropolis<P> {
+  /// Distribution the new values of the sites are drawn from.
+  pub proposal: P,
+
+  /// Number of steps attempted on a site before moving on to the next one.
+  hits: usize,
+
+  acceptance: Acceptance,
+
+  /// Tuning of the proposal width, if enabled.
+  #[serde(default)]
+  tuning: Option<Tuning>,
+}
+
+impl<P> Metropolis<P> {
+  /// Constructs a helper attempting a single step on every site.
+  pub fn new(proposal: P) -> Metropolis<P> {
+    Metropolis {
+      proposal,
+      hits: 1,
+      acceptance: Acceptance::new(),
+      tuning: None,
+    }
+  }
+
+  /// Attempts `hits` steps on every site before moving on to the next one.
+  /// Multiple hits are cheaper than multiple sweeps when the local action is
+  /// cheap compared to visiting a site.
+  pub fn with_hits(mut self, hits: usize) -> Metropolis<P> {
+    assert!(hits > 0, "At least one hit per site is required.");
+    self.hits = hits;
+    self
+  }
+
+  /// Tunes the width of the proposal toward the `target` acceptance rate
+  /// while the sample is being thermalized, adjusting it after every 100
+  /// local steps. Once the thermalization is over, the width is frozen, so
+  /// that the measured samples satisfy detailed balance. The sample has to
+  /// call `report(..)` in `Sample::report_statistics` for the width to be
+  /// frozen. Samples thermalized again on refresh keep the frozen width.
+  /// Requires a proposal with a tunable width, such as `UniformShift` or
+  /// `GaussianShift`.
+  pub fn with_tuning(mut self, target: f64) -> Metropolis<P> {
+    assert!(target > 0.0 && target < 1.0,
+            "The target acceptance rate should be within (0, 1).");
+    self.tuning = Some(Tuning {
+      target,
+      batch: Acceptance::new(),
+      width: None,
+      frozen: false,
+    });
+    self
+  }
+
+  /// Decides on a step changing the action by `delta_s` with `accept(..)`,
+  /// and records it. Use it for updates which are not local.
+  pub fn accept(&mut self, delta_s: f64, rng: &mut Rng) -> bool {
+    let accepted = accept(delta_s, rng);
+    self.acceptance.record(accepted);
+    accepted
+  }
+
+  /// Attempts the configured number of steps on the `site` of `sites`.
+  /// Returns the number of accepted steps.
+  pub fn update_site<L>(&mut self, sites: &mut L, site: usize, rng: &mut Rng)
+         -> usize
+    where L: Sites, P: Proposal<L::Value> {
+    let mut accepted = 0;
+    for _ in 0..self.hits {
+      let old_value = sites.site(site);
+      let old_s = sites.local_action(site);
+      let new_value = self.proposal.propose(&old_value, rng);
+      sites.set_site(site, new_value);
+      let delta_s = sites.local_action(site) - old_s;
+      let step_accepted = self.accept(delta_s, rng);
+      if step_accepted {
+        accepted += 1;
+      } else {
+        sites.set_site(site, old_value);
+      }
+      if let Some(ref mut tuning) = self.tuning {
+        if !tuning.frozen {
+          tuning.record(step_accepted, &mut self.proposal);
+        }
+      }
+    }
+    accepted
+  }
+
+  /// Updates every site of `sites` in order. Returns the number of accepted
+  /// steps.
+  pub fn sweep<L>(&mut self, sites: &mut L, rng: &mut Rng) -> usize
+    where L: Sites, P: Proposal<L::Value> {
+    (0..sites.num_sites())
+      .map(|site| self.update_site(sites, site, rng))
+      .sum()
+  }
+
+  /// Gives the steps recorded since the last report.
+  pub fn acceptance(&self) -> &Acceptance {
+    &self.acceptance
+  }
+
+  /// Records the acceptance rate since the last report in the diagnostic
+  /// measure `__ergothic/<name>`. See `Acceptance::report(..)`. If the width
+  /// of the proposal is tuned, it is recorded as `__ergothic/<name>/width`,
+  /// and frozen and logged once the thermalization is over.
+  pub fn report(&mut self, name: &str, stats: &mut UpdateStats) {
+    if let Some(ref mut tuning) = self.tuning {
+      if stats.thermalization_finished() && !tuning.frozen {
+        tuning.frozen = true;
+        match tuning.width {
+          Some(width) => info!(
+            "Tuned the proposal of '{}' to the width {} for the acceptance \
+             rate {}.", name, width, tuning.target),
+          None => warn!("The proposal of '{}' wasn't tuned, since no steps \
+                         were made during the thermalization.", name),
+        }
+      }
+      if let Some(width) = tuning.width {
+        stats.record(&format!("{}/width", name), width);
+      }
+    }
+    self.acceptance.report(name, stats);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Acceptance;
+  use super::Metropolis;
+  use super::Proposal;
+  use super::Sites;
+  use super::UniformShift;
+  use super::accept;
+  use ::measure::Measures;
+  use ::rng::Rng;
+  use ::rng::chain_rng;
+  use ::statistics::UpdateStats;
+
+  /// Independent harmonic oscillators, with the action x²/2 on each site.
+  struct Harmonic(Vec<f64>);
+
+  impl Sites for Harmonic {
+    type Value = f64;
+
+    fn num_sites(&self) -> usize {
+      self.0.len()
+    }
+
+    fn site(&self, site: usize) -> f64 {
+      self.0[site]
+    }
+
+    fn set_site(&mut self, site: usize, value: f64) {
+      self.0[site] = value;
+    }
+
+    fn local_action(&self, site: usize) -> f64 {
+      self.0[site].powi(2) / 2.0
+    }
+  }
+
+  /// Gives the width of the tuned proposal and the acceptance rate over
+  /// `sweeps` further sweeps.
+  fn run(metropolis: &mut Metropolis<UniformShift>, sweeps: usize,
+         rng: &mut Rng) -> (f64, f64) {
+    let mut sites = Harmonic(vec![0.0; 10]);
+    let proposed = metropolis.acceptance().proposed();
+    let accepted = metropolis.acceptance().accepted();
+    for _ in 0..sweeps {
+      metropolis.sweep(&mut sites, rng);
+    }
+    let proposed = metropolis.acceptance().proposed() - proposed;
+    let accepted = metropolis.acceptance().accepted() - accepted;
+    (metropolis.proposal.width, accepted as f64 / proposed as f64)
+  }
+
+  #[test]
+  fn accept_decreasing_action() {
+    let mut rng = chain_rng(1, 0, 0);
+    assert!((0..100).all(|_| accept(-1.0, &mut rng)));
+    assert!((0..100).all(|_| accept(0.0, &mut rng)));
+    assert!(!(0..100).any(|_| accept(100.0, &mut rng)));
+  }
+
+  #[test]
+  fn acceptance_report_resets() {
+    let mut acceptance = Acceptance::new();
+    assert_eq!(acceptance.rate(), None);
+    acceptance.record(true);
+    acceptance.record(false);
+    acceptance.record(false);
+    acceptance.record(true);
+    assert_eq!(acceptance.rate(), Some(0.5));
+    let mut measures = Measures::new_empty();
+    acceptance.report("acceptance", &mut UpdateStats::new(&mut measures));
+    assert_eq!(acceptance.proposed(), 0);
+    let idx = measures.find("__ergothic/acceptance").unwrap();
+    assert_eq!(measures.get(idx).acc.value(), 0.5);
+  }
+
+  #[test]
+  fn width_is_fixed_without_tuning() {
+    let mut rng = chain_rng(1, 0, 0);
+    let mut metropolis = Metropolis::new(UniformShift { width: 15.0 });
+    let (width, rate) = run(&mut metropolis, 100, &mut rng);
+    assert_eq!(width, 15.0);
+    assert!(rate < 0.3, "Acceptance rate {}.", rate);
+  }
+
+  #[test]
+  fn tuning_reaches_target_rate() {
+    let mut rng = chain_rng(1, 0, 0);
+    let mut metropolis = Metropolis::new(UniformShift { width: 15.0 })
+      .with_tuning(0.5);
+    run(&mut metropolis, 1000, &mut rng);
+    let (width, rate) = run(&mut metropolis, 1000, &mut rng);
+    assert!(width > 1.0 && width < 5.0, "Tuned to the width {}.", width);
+    assert!((rate - 0.5).abs() < 0.05, "Acceptance rate {}.", rate);
+  }
+
+  #[test]
+  fn width_is_frozen_after_thermalization() {
+    let mut rng = chain_rng(1, 0, 0);
+    let mut metropolis = Metropolis::new(UniformShift { width: 15.0 })
+      .with_tuning(0.5);
+    run(&mut metropolis, 10, &mut rng);
+    metropolis.report("acceptance", &mut UpdateStats::after_thermalization());
+    let frozen = metropolis.proposal.width;
+    assert!(frozen < 15.0, "The width hasn't been tuned.");
+    let (width, _) = run(&mut metropolis, 100, &mut rng);
+    assert_eq!(width, frozen);
+
+    let mut measures = Measures::new_empty();
+    metropolis.report("acceptance", &mut UpdateStats::new(&mut measures));
+    let idx = measures.find("__ergothic/acceptance/width").unwrap();
+    assert_eq!(measures.get(idx).acc.value(), frozen);
+  }
+
+  /// A proposal without a tunable width.
+  struct Flip;
+
+  impl Proposal<f64> for Flip {
+    fn propose(&self, current: &f64, _rng: &mut Rng) -> f64 {
+      -current
+    }
+  }
+
+  #[test]
+  #[should_panic(expected = "requires a proposal with a tunable width")]
+  fn tuning_requires_tunable_width() {
+    let mut rng = chain_rng(1, 0, 0);
+    let mut metropolis = Metropolis::new(Flip).with_tuning(0.5);
+    metropolis.sweep(&mut Harmonic(vec![1.0]), &mut rng);
+  }
+}
@@ -0,0 +1,41 @@
+use ::std::any::Any;
+
+/// What happens to a chain whose sample or measurement function panicked. In
+/// either case, the values recorded for the offending sample are discarded,
+/// and the panic message is reported to the exporter. Panics while refreshing
+/// a sample or preparing it again are handled the same way, but panics while
+/// preparing the initial samples are not caught.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PanicPolicy {
+  /// Discard the sample, prepare and thermalize a new one, and continue the
+  /// simulation.
+  Reprepare,
+
+  /// Stop the simulation and flush the values accumulated so far. When
+  /// running from the command line, the process then exits with
+  /// `EXIT_CODE_PANICKED`.
+  Exit,
+}
+
+impl ::std::str::FromStr for PanicPolicy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<PanicPolicy, String> {
+    match s {
+      "reprepare" => Ok(PanicPolicy::Reprepare),
+      "exit" => Ok(PanicPolicy::Exit),
+      _ => Err(format!("Expected 'reprepare' or 'exit', found '{}'.", s)),
+    }
+  }
+}
+
+/// Extracts the message from the payload of a caught panic.
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+  if let Some(message) = payload.downcast_ref::<&str>() {
+    message.to_string()
+  } else if let Some(message) = payload.downcast_ref::<String>() {
+    message.clone()
+  } else {
+    "Unknown panic payload.".to_string()
+  }
+}
@@ -0,0 +1,48 @@
+use ::std::time::Duration;
+
+/// What happens to the sample of a chain when it is refreshed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RefreshMode {
+  /// Discard the sample, prepare and thermalize a new one.
+  Prepare,
+
+  /// Thermalize the current sample again.
+  Thermalize,
+}
+
+impl ::std::str::FromStr for RefreshMode {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<RefreshMode, String> {
+    match s {
+      "prepare" => Ok(RefreshMode::Prepare),
+      "thermalize" => Ok(RefreshMode::Thermalize),
+      _ => Err(format!("Expected 'prepare' or 'thermalize', found '{}'.", s)),
+    }
+  }
+}
+
+/// Policy of periodically refreshing the samples of the chains. Refreshing
+/// guards against a chain getting stuck in a topological sector or in a
+/// metastable state, and improves ergodicity. By default, samples are never
+/// refreshed.
+#[derive(Clone, Debug)]
+pub struct RefreshPolicy {
+  /// What happens to the sample on refresh.
+  pub mode: RefreshMode,
+
+  /// Refresh the sample of each chain after drawing this many samples.
+  pub every_samples: Option<usize>,
+
+  /// Refresh the sample of each chain after running for this long.
+  pub every_duration: Option<Duration>,
+}
+
+impl RefreshPolicy {
+  /// Returns true if the sample of a chain should be refreshed, given the
+  /// number of `samples` drawn and the time `elapsed` since the last refresh.
+  pub fn is_due(&self, samples: usize, elapsed: Duration) -> bool {
+    self.every_samples.map_or(false, |every| samples >= every) ||
+      self.every_duration.map_or(false, |every| elapsed >= every)
+  }
+}
@@ -0,0 +1,97 @@
+use ::rand::SeedableRng;
+
+/// Pseudo-random number generator owned by the simulation engine. Every chain
+/// has its own generator, which is handed to the methods of `SeededSample`.
+pub type Rng = ::rand::prng::IsaacRng;
+
+/// Seeds are limited to 63 bits, so that they can be stored in BSON as signed
+/// integers.
+pub const MAX_SEED: u64 = (1 << 63) - 1;
+
+/// Draws a random seed for simulations which are not given one explicitly.
+pub fn random_seed() -> u64 {
+  ::rand::random::<u64>() & MAX_SEED
+}
+
+/// The SplitMix64 finalizer. Maps nearby inputs to unrelated outputs.
+fn mix(mut z: u64) -> u64 {
+  z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+  z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+  z ^ (z >> 31)
+}
+
+/// Increment of the SplitMix64 generator.
+const GOLDEN_GAMMA: u64 = 0x9e3779b97f4a7c15;
+
+/// Deterministically derives the seed of the `index`-th of several
+/// consecutive runs of the simulation from the `seed` given to the process.
+pub fn derive_seed(seed: u64, index: u64) -> u64 {
+  mix(mix(seed) ^ index.wrapping_mul(GOLDEN_GAMMA)) & MAX_SEED
+}
+
+/// Deterministically derives the generator of the `chain`-th chain running on
+/// the `node`-th node from the simulation `seed`. Different chains get
+/// independent streams of random numbers.
+pub fn chain_rng(seed: u64, node: u64, chain: u64) -> Rng {
+  let mut state = mix(mix(mix(seed) ^ node) ^ chain);
+  let mut key = [0u8; 32];
+  for chunk in key.chunks_mut(8) {
+    state = state.wrapping_add(GOLDEN_GAMMA);
+    chunk.copy_from_slice(&mix(state).to_le_bytes());
+  }
+  Rng::from_seed(key)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::MAX_SEED;
+  use super::Rng;
+  use super::chain_rng;
+  use super::derive_seed;
+  use super::random_seed;
+  use ::rand::RngCore;
+  use ::std::collections::HashSet;
+
+  fn stream(mut rng: Rng) -> Vec<u64> {
+    (0..8).map(|_| rng.next_u64()).collect()
+  }
+
+  #[test]
+  fn seeds_fit_in_63_bits() {
+    for _ in 0..100 {
+      assert!(random_seed() <= MAX_SEED);
+    }
+    for index in 0..100 {
+      assert!(derive_seed(u64::MAX, index) <= MAX_SEED);
+      assert!(derive_seed(0, u64::MAX - index) <= MAX_SEED);
+    }
+  }
+
+  #[test]
+  fn derived_seeds_are_reproducible_and_distinct() {
+    assert_eq!(derive_seed(17, 3), derive_seed(17, 3));
+    let seeds: HashSet<u64> = (0..1000).map(|index| derive_seed(17, index))
+      .chain((0..1000).map(|seed| derive_seed(seed, 0)))
+      .collect();
+    // derive_seed(17, 0) appears in both ranges.
+    assert_eq!(seeds.len(), 1999);
+  }
+
+  #[test]
+  fn chain_streams_are_reproducible() {
+    assert_eq!(stream(chain_rng(42, 1, 2)), stream(chain_rng(42, 1, 2)));
+  }
+
+  #[test]
+  fn chain_streams_are_distinct() {
+    let mut streams = HashSet::new();
+    for seed in 0..4 {
+      for node in 0..4 {
+        for chain in 0..4 {
+          streams.insert(stream(chain_rng(seed, node, chain)));
+        }
+      }
+    }
+    assert_eq!(streams.len(), 64);
+  }
+}
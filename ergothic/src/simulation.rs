@@ -1,5 +1,33 @@
+use ::checkpoint::CheckpointSettings;
+use ::checkpoint::Checkpointer;
+use ::checkpoint::Codec;
+use ::equilibrate::EquilibrationSettings;
+use ::equilibrate::Thermalizer;
+use ::equilibrate::Tracer;
+use ::export_thread::ExportThread;
+use ::hooks::Hooks;
+use ::measure::MeasureIdx;
 use ::measure::Measures;
+use ::panics::PanicPolicy;
+use ::panics::panic_message;
+use ::refresh::RefreshMode;
+use ::refresh::RefreshPolicy;
+use ::rng::Rng;
+use ::serde::Serialize;
+use ::serde::de::DeserializeOwned;
+use ::statistics::UpdateStats;
+use ::stop::Results;
+use ::tempering::Ensemble;
+use ::tempering::TemperedSample;
+use ::stop::StopCriteria;
+use ::stop::StopReason;
+use ::std::panic::AssertUnwindSafe;
+use ::std::sync::Mutex;
+use ::std::sync::atomic::AtomicBool;
+use ::std::sync::atomic::AtomicUsize;
+use ::std::sync::atomic::Ordering;
 use ::std::time::Duration;
+use ::std::time::Instant;
 use ::std::time::SystemTime;
 
 /// A configuration sample from the ergodic distribution must implement this
@@ -28,6 +56,529 @@ pub trait Sample {
   /// The most common implementation of `mutate` uses the Metropolis algorithm.
   /// You may want to check out the `metropolis` module for useful helpers.
   fn mutate(&mut self);
+
+  /// Gives the action of the sample configuration, if the sample is able to
+  /// report it. The simulation engine records it as the diagnostic measure
+  /// `__ergothic/action`. The default implementation reports nothing.
+  fn action(&self) -> Option<f64> {
+    None
+  }
+
+  /// Reports the statistics of the update algorithm collected since the last
+  /// call, such as the acceptance rate kept by the `metropolis` helpers. The
+  /// simulation engine calls it after mutating the sample and records the
+  /// statistics as diagnostic measures. Statistics collected while
+  /// thermalizing the sample are discarded. The default implementation
+  /// reports nothing.
+  fn report_statistics(&mut self, _stats: &mut UpdateStats) {}
+}
+
+/// A variant of `Sample` whose methods are handed the random number generator
+/// owned by the simulation engine. Each chain has its own generator, derived
+/// deterministically from the seed of the simulation, so that runs can be
+/// reproduced. Every `Sample` is a `SeededSample` which ignores the generator.
+pub trait SeededSample {
+  /// Creates a new configuration sample with randomized degrees of freedom.
+  fn prepare(rng: &mut Rng) -> Self;
+
+  /// Thermalizes the configuration sample. See `Sample::thermalize`.
+  fn thermalize(&mut self, rng: &mut Rng) {
+    for _ in 0..20 {
+      self.mutate(rng);
+    }
+  }
+
+  /// Makes a randomized step in the configuration space. See
+  /// `Sample::mutate`.
+  fn mutate(&mut self, rng: &mut Rng);
+
+  /// Gives the action of the sample configuration. See `Sample::action`.
+  fn action(&self) -> Option<f64> {
+    None
+  }
+
+  /// Reports the statistics of the update algorithm. See
+  /// `Sample::report_statistics`.
+  fn report_statistics(&mut self, _stats: &mut UpdateStats) {}
+}
+
+impl<S: Sample> SeededSample for S {
+  fn prepare(_rng: &mut Rng) -> S {
+    <S as Sample>::prepare()
+  }
+
+  fn thermalize(&mut self, _rng: &mut Rng) {
+    <S as Sample>::thermalize(self)
+  }
+
+  fn mutate(&mut self, _rng: &mut Rng) {
+    <S as Sample>::mutate(self)
+  }
+
+  fn action(&self) -> Option<f64> {
+    <S as Sample>::action(self)
+  }
+
+  fn report_statistics(&mut self, stats: &mut UpdateStats) {
+    <S as Sample>::report_statistics(self, stats)
+  }
+}
+
+/// A variant of `SeededSample` which is prepared with parameters given on the
+/// command line, so that changing them doesn't require recompiling the
+/// simulation. Every `SeededSample` is a `ParameterizedSample` without
+/// parameters.
+pub trait ParameterizedSample {
+  /// Parameters of the sample, such as the size of the lattice or the
+  /// couplings. Deserialized from the command line arguments `--param` and
+  /// `--params_file`, and recorded in every exported data point. If neither
+  /// is given, the default parameters are used. Mark the struct with
+  /// `#[serde(default)]` to allow giving only some of the parameters.
+  type Params: Serialize + DeserializeOwned + Default + Sync;
+
+  /// Creates a new configuration sample with randomized degrees of freedom.
+  fn prepare(params: &Self::Params, rng: &mut Rng) -> Self;
+
+  /// Thermalizes the configuration sample. See `Sample::thermalize`.
+  fn thermalize(&mut self, rng: &mut Rng) {
+    for _ in 0..20 {
+      self.mutate(rng);
+    }
+  }
+
+  /// Makes a randomized step in the configuration space. See
+  /// `Sample::mutate`.
+  fn mutate(&mut self, rng: &mut Rng);
+
+  /// Gives the action of the sample configuration. See `Sample::action`.
+  fn action(&self) -> Option<f64> {
+    None
+  }
+
+  /// Reports the statistics of the update algorithm. See
+  /// `Sample::report_statistics`.
+  fn report_statistics(&mut self, _stats: &mut UpdateStats) {}
+}
+
+impl<S: SeededSample> ParameterizedSample for S {
+  type Params = ();
+
+  fn prepare(_params: &(), rng: &mut Rng) -> S {
+    <S as SeededSample>::prepare(rng)
+  }
+
+  fn thermalize(&mut self, rng: &mut Rng) {
+    <S as SeededSample>::thermalize(self, rng)
+  }
+
+  fn mutate(&mut self, rng: &mut Rng) {
+    <S as SeededSample>::mutate(self, rng)
+  }
+
+  fn action(&self) -> Option<f64> {
+    <S as SeededSample>::action(self)
+  }
+
+  fn report_statistics(&mut self, stats: &mut UpdateStats) {
+    <S as SeededSample>::report_statistics(self, stats)
+  }
+}
+
+/// Indices of the diagnostic measures recorded by a single chain.
+struct ChainDiagnostics {
+  mutate_time: MeasureIdx,
+  measure_time: MeasureIdx,
+  action: Option<MeasureIdx>,
+}
+
+impl ChainDiagnostics {
+  /// Registers the diagnostic measures. The action is only recorded if the
+  /// `sample` reports it.
+  fn register<S: ParameterizedSample>(measures: &mut Measures, sample: &S)
+     -> ChainDiagnostics {
+    ChainDiagnostics {
+      mutate_time: measures.find_or_register_diagnostic("mutate_time"),
+      measure_time: measures.find_or_register_diagnostic("measure_time"),
+      action: sample.action()
+        .map(|_| measures.find_or_register_diagnostic("action")),
+    }
+  }
+}
+
+/// A Markov chain of configuration samples. Each chain drives its own sample,
+/// independently of other chains running in the same process.
+pub struct Chain<S> {
+  sample: S,
+  rng: Rng,
+  diagnostics: ChainDiagnostics,
+
+  /// Number of samples drawn since the sample was last refreshed.
+  samples_since_refresh: usize,
+  refreshed_at: Instant,
+}
+
+impl<S: ParameterizedSample> Chain<S> {
+  /// Prepares and thermalizes a new sample. Returns the number of mutations
+  /// performed by the adaptive thermalization, if it ran.
+  fn prepare(rng: &mut Rng, thermalizer: &Thermalizer<S>)
+     -> (S, Option<usize>) {
+    let mut sample = S::prepare(&thermalizer.params, rng);
+    let thermalization = thermalizer.thermalize(&mut sample, rng);
+    (sample, thermalization)
+  }
+
+  /// Restores a sample and its generator from a checkpoint, or prepares and
+  /// thermalizes a new sample using the generator `rng` if there is none.
+  fn restore_or_prepare(restored: Option<(::bson::Bson, Rng)>,
+                        codec: Option<Codec<S>>, mut rng: Rng,
+                        thermalizer: &Thermalizer<S>)
+     -> (S, Rng, Option<usize>) {
+    match (restored, codec) {
+      (Some((sample, rng)), Some(codec)) => (codec.decode(sample), rng, None),
+      _ => {
+        let (sample, thermalization) = Chain::prepare(&mut rng, thermalizer);
+        (sample, rng, thermalization)
+      },
+    }
+  }
+
+  /// Constructs a chain driving a thermalized `sample` with the generator
+  /// `rng`. Registers diagnostic measures in `measures`. The length of the
+  /// adaptive `thermalization`, if it ran, is recorded as well.
+  pub fn new(sample: S, rng: Rng, thermalization: Option<usize>,
+             measures: &mut Measures) -> Chain<S> {
+    let diagnostics = ChainDiagnostics::register(measures, &sample);
+    Chain::<S>::record_thermalization(measures, thermalization);
+    Chain {
+      sample,
+      rng,
+      diagnostics,
+      samples_since_refresh: 0,
+      refreshed_at: Instant::now(),
+    }
+  }
+
+  /// Records the length of the adaptive `thermalization`, if it ran.
+  fn record_thermalization(measures: &mut Measures,
+                           thermalization: Option<usize>) {
+    if let Some(thermalization) = thermalization {
+      let idx = measures.find_or_register_diagnostic("thermalization_length");
+      measures.accumulate(idx, thermalization as f64);
+    }
+  }
+
+  /// Returns true if the sample is due for a refresh according to `policy`.
+  fn refresh_due(&self, policy: &Option<RefreshPolicy>) -> bool {
+    policy.as_ref().map_or(false, |policy| policy.is_due(
+      self.samples_since_refresh, self.refreshed_at.elapsed()))
+  }
+
+  /// Refreshes the sample according to `mode`. Returns the number of
+  /// mutations performed by the adaptive thermalization, if it ran. Doesn't
+  /// record any measures, so that a chain running in a separate thread doesn't
+  /// have to hold the lock on its tally while thermalizing.
+  fn refresh(&mut self, mode: RefreshMode, thermalizer: &Thermalizer<S>)
+     -> Option<usize> {
+    info!("Refreshing the sample after {} samples.",
+          self.samples_since_refresh);
+    let thermalization = match mode {
+      RefreshMode::Prepare => {
+        let (sample, thermalization) =
+          Chain::prepare(&mut self.rng, thermalizer);
+        self.sample = sample;
+        thermalization
+      },
+      RefreshMode::Thermalize =>
+        thermalizer.thermalize(&mut self.sample, &mut self.rng),
+    };
+    self.samples_since_refresh = 0;
+    self.refreshed_at = Instant::now();
+    thermalization
+  }
+
+  /// Same as `refresh(..)`, but if a panic `policy` is given, a panic in the
+  /// sample is caught and its message is returned. The sample has to be
+  /// prepared again after such a panic.
+  fn try_refresh(&mut self, mode: RefreshMode, thermalizer: &Thermalizer<S>,
+                 policy: Option<PanicPolicy>) -> Result<Option<usize>, String> {
+    if policy.is_none() {
+      return Ok(self.refresh(mode, thermalizer));
+    }
+    ::std::panic::catch_unwind(AssertUnwindSafe(
+      || self.refresh(mode, thermalizer)))
+      .map_err(|payload| panic_message(&*payload))
+  }
+
+  /// Gives the sample driven by the chain.
+  pub fn sample(&self) -> &S {
+    &self.sample
+  }
+
+  /// Gives the sample driven by the chain for modification outside of the
+  /// Markov process, such as swapping it with another chain.
+  pub fn sample_mut(&mut self) -> &mut S {
+    &mut self.sample
+  }
+
+  /// Serializes the sample and copies the generator for a checkpoint.
+  fn snapshot(&self, codec: Codec<S>) -> (Result<::bson::Bson, String>, Rng) {
+    (codec.encode(&self.sample), self.rng.clone())
+  }
+
+  /// Draws the next sample from the ergodic distribution and records the
+  /// values of observables in `measures`. The sample is mutated `mutations`
+  /// times before measuring, which reduces autocorrelation between subsequent
+  /// measurements.
+  pub fn step<F>(&mut self, mutations: usize, measures: &mut Measures,
+                 measure_fn: &mut F)
+    where F: FnMut(&S, &mut Measures) {
+    // Mutate the sample. This draws a new configuration from the ergodic
+    // distribution.
+    for _ in 0..mutations {
+      let mutate_start = Instant::now();
+      self.sample.mutate(&mut self.rng);
+      measures.accumulate(self.diagnostics.mutate_time,
+                          secs(mutate_start.elapsed()));
+    }
+    self.sample.report_statistics(&mut UpdateStats::new(measures));
+    if let Some(action_idx) = self.diagnostics.action {
+      if let Some(action) = self.sample.action() {
+        measures.accumulate(action_idx, action);
+      }
+    }
+
+    // Measure and record the values of observables. Measures which are not
+    // due on this sample according to their cadence are skipped.
+    if measures.any_due() {
+      let measure_start = Instant::now();
+      measure_fn(&self.sample, measures);
+      measures.accumulate(self.diagnostics.measure_time,
+                          secs(measure_start.elapsed()));
+    }
+    measures.next_step();
+    self.samples_since_refresh += 1;
+  }
+
+  /// Same as `step(..)`, but a panic in the sample or in `measure_fn` is
+  /// caught. The values recorded for the offending sample are discarded, and
+  /// the panic message is returned. Saving the values before every step makes
+  /// it slower than `step(..)`.
+  fn try_step<F>(&mut self, mutations: usize, measures: &mut Measures,
+                 measure_fn: &mut F) -> Result<(), String>
+    where F: FnMut(&S, &mut Measures) {
+    let saved = measures.save();
+    let stepped = ::std::panic::catch_unwind(AssertUnwindSafe(
+      || self.step(mutations, measures, measure_fn)));
+    stepped.map_err(|payload| {
+      measures.restore(saved);
+      panic_message(&*payload)
+    })
+  }
+}
+
+/// Serialized sample of a chain running in a separate thread, taken for a
+/// checkpoint.
+struct Snapshot {
+  /// Incremented by the exporting thread every time it requests snapshots.
+  epoch: usize,
+  chain: (Result<::bson::Bson, String>, Rng),
+
+  /// Values measured up to the snapshot, which the checkpoint must contain.
+  measures: Measures,
+}
+
+/// Measures accumulated by a chain running in a separate thread, along with the
+/// number of samples drawn since the last flush.
+struct Tally {
+  measures: Measures,
+  samples: usize,
+
+  /// False while the chain is preparing or refreshing its sample, or after
+  /// a panic until the sample is prepared again.
+  running: bool,
+  snapshot: Option<Snapshot>,
+
+  /// Messages of the panics caught since the last flush.
+  panics: Vec<String>,
+}
+
+impl Tally {
+  /// Takes a snapshot of the `chain` for a checkpoint. The measures
+  /// accumulated so far are moved into the snapshot.
+  fn snapshot<S>(&mut self, epoch: usize, codec: Codec<S>, chain: &Chain<S>)
+    where S: ParameterizedSample {
+    let mut measures = self.measures.clone();
+    self.measures.reset();
+    if let Some(previous) = self.snapshot.take() {
+      measures.merge(&previous.measures);
+    }
+    self.snapshot = Some(Snapshot {
+      epoch,
+      chain: chain.snapshot(codec),
+      measures,
+    });
+  }
+
+  /// Drops the snapshot taken earlier, if any, keeping its measures.
+  fn drop_snapshot(&mut self) {
+    if let Some(previous) = self.snapshot.take() {
+      self.measures.merge(&previous.measures);
+    }
+  }
+}
+
+/// Keeps track of flush timing and export errors. Also keeps the total of all
+/// exported values, which is returned once the simulation stops. The data
+/// points are exported in a background thread.
+struct Flusher<'a, S: 'a> {
+  hooks: &'a mut Hooks<S>,
+  exports: ExportThread,
+  last_export_timestamp: SystemTime,
+  export_errors_in_row: usize,
+  samples_per_sec: MeasureIdx,
+  total: Measures,
+}
+
+impl<'a, S> Flusher<'a, S> {
+  /// Starts the export thread, which takes over the exporter of the
+  /// simulation.
+  fn new(parameters: &mut Parameters, hooks: &'a mut Hooks<S>)
+     -> Flusher<'a, S> {
+    let exporter = parameters.exporter.take()
+      .expect("The exporter has already been taken.");
+    Flusher {
+      hooks,
+      exports: ExportThread::start(exporter, parameters.export_queue_len,
+                                   FINAL_FLUSH_RETRY_INTERVAL),
+      last_export_timestamp: SystemTime::now(),
+      export_errors_in_row: 0,
+      samples_per_sec:
+        parameters.measures.find_or_register_diagnostic("samples_per_sec"),
+      total: Measures::new_empty(),
+    }
+  }
+
+  /// Gives the time remaining until the next flush is due.
+  fn time_until_due(&self, parameters: &Parameters) -> Duration {
+    let elapsed = self.last_export_timestamp.elapsed().unwrap();
+    if elapsed >= parameters.flush_interval {
+      Duration::from_secs(0)
+    } else {
+      parameters.flush_interval - elapsed
+    }
+  }
+
+  /// Records the sampling rate since the last flush. `samples` is the number
+  /// of samples drawn since the last flush.
+  fn record_rate(&mut self, parameters: &mut Parameters, samples: usize) {
+    let since_last_export = self.last_export_timestamp.elapsed().unwrap();
+    self.last_export_timestamp = SystemTime::now();
+    parameters.measures.accumulate(
+        self.samples_per_sec, samples as f64 / secs(since_last_export));
+  }
+
+  /// Hands a new data point containing the accumulated expectations over to
+  /// the export thread, and resets them. Failed exports are retried by the
+  /// export thread.
+  fn export(&mut self, parameters: &mut Parameters) {
+    self.hooks.before_flush(&parameters.measures);
+    self.exports.export(&parameters.metadata, &parameters.measures);
+    self.total.merge(&parameters.measures);
+    parameters.measures.reset();
+    self.check_results();
+  }
+
+  /// Processes the results of the exports finished since the last call.
+  fn check_results(&mut self) {
+    for result in self.exports.results() {
+      self.hooks.after_flush(&result);
+      match result {
+        Ok(()) => self.export_errors_in_row = 0,
+        Err(::export::ExportError(ref err)) => {
+          self.export_errors_in_row += 1;
+          // The export thread keeps the values in hope of exporting them the
+          // next time.
+          error!("Failed to export measured values: {:?}", err);
+        },
+      }
+    }
+  }
+
+  /// Reports a panic caught in the simulation to the exporter. Failed reports
+  /// are not retried.
+  fn report_panic(&mut self, parameters: &Parameters, message: &str) {
+    error!("Caught a panic, discarding the offending sample: {}", message);
+    self.exports.report_panic(&parameters.metadata, message);
+  }
+
+  /// Exports a new data point containing the accumulated expectations.
+  /// `samples` is the number of samples drawn since the last flush.
+  fn flush(&mut self, parameters: &mut Parameters, samples: usize) {
+    self.record_rate(parameters, samples);
+    self.export(parameters);
+    if let Some(ref max_export_errors_in_row) =
+           parameters.max_export_errors_in_row {
+      if self.export_errors_in_row >= *max_export_errors_in_row {
+        panic!("Reached a maximum of {} export errors in row.",
+               *max_export_errors_in_row);
+      }
+    }
+  }
+
+  /// Gives the values accumulated over the whole run, including the ones that
+  /// haven't been exported yet.
+  fn accumulated(&self, parameters: &Parameters) -> Measures {
+    let mut accumulated = self.total.clone();
+    accumulated.merge(&parameters.measures);
+    accumulated
+  }
+
+  /// Gives the values which haven't been exported yet, to be written to a
+  /// checkpoint. Doesn't wait for the export in progress, whose values are
+  /// included since it may still fail. A checkpoint with values which were
+  /// already waiting before the last flush is rewritten once they are
+  /// exported.
+  fn unexported(&mut self, parameters: &Parameters) -> Measures {
+    let mut unexported = self.exports.pending();
+    unexported.merge(&parameters.measures);
+    unexported
+  }
+
+  /// Gives the number of flushes so far, and the last flush whose values have
+  /// all been exported.
+  fn progress(&mut self) -> (usize, usize) {
+    self.exports.progress()
+  }
+
+  /// Flushes the remaining values when the simulation stops, and constructs
+  /// the results. Failed exports are retried until
+  /// `parameters.shutdown_grace_period` runs out. The values which haven't
+  /// been exported by then are kept in `parameters.measures`.
+  fn finish(mut self, parameters: &mut Parameters, samples: usize,
+            stop_reason: StopReason) -> Results {
+    info!("Stopping the simulation: {:?}.", stop_reason);
+    let deadline = Instant::now() + parameters.shutdown_grace_period;
+    if !parameters.measures.is_empty() {
+      self.record_rate(parameters, samples);
+      self.export(parameters);
+    }
+    let undelivered = self.exports.close(deadline);
+    self.check_results();
+    let results = Results::new(stop_reason, undelivered.is_none(),
+                               &self.accumulated(parameters));
+    if let Some(undelivered) = undelivered {
+      error!("Failed to flush the remaining values before the shutdown grace \
+              period ran out.");
+      parameters.measures.merge(&undelivered);
+    }
+    self.hooks.stop(&results);
+    results
+  }
+}
+
+/// Converts a duration to seconds.
+pub fn secs(duration: Duration) -> f64 {
+  duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9
 }
 
 /// Simulation parameters.
@@ -40,63 +591,806 @@ pub struct Parameters {
   pub measures: ::measure::Measures,
 
   /// The polymorphic data exporter. Simulation engine will send measured data
-  /// to the exporter every `flush_interval` seconds.
-  pub exporter: Box<dyn (::export::Exporter)>,
+  /// to the exporter every `flush_interval` seconds. Taken over by the export
+  /// thread when the simulation starts.
+  pub exporter: Option<Box<dyn (::export::Exporter) + Send>>,
+
+  /// Maximum number of data points waiting for the export thread. Values
+  /// flushed while the queue is full are merged into the last waiting data
+  /// point.
+  pub export_queue_len: usize,
   
   /// Interval between subsequent flushes of the accumulated values.
   pub flush_interval: Duration,
 
   /// Panic after this many export errors in a row.
   pub max_export_errors_in_row: Option<usize>,
+
+  /// Number of independent chains to run in separate threads.
+  pub threads: usize,
+
+  /// Conditions under which the simulation stops.
+  pub stop: StopCriteria,
+
+  /// Install handlers for SIGINT and SIGTERM, which stop the simulation
+  /// gracefully: the current step is finished, and the remaining values are
+  /// flushed.
+  pub handle_signals: bool,
+
+  /// When the simulation stops, failed attempts to flush the remaining values
+  /// are retried for this long.
+  pub shutdown_grace_period: Duration,
+
+  /// Adaptive thermalization, which replaces `Sample::thermalize` if given.
+  pub equilibration: Option<EquilibrationSettings>,
+
+  /// Periodically refresh the samples of the chains.
+  pub refresh: Option<RefreshPolicy>,
+
+  /// Catch panics in the sample and in the measurement function. If `None`,
+  /// a panic kills the process.
+  pub on_panic: Option<PanicPolicy>,
+
+  /// Parameters of the sample given on the command line, deserialized into
+  /// `ParameterizedSample::Params` when the simulation starts. If `None`, the
+  /// default parameters are used.
+  pub params: Option<::serde_json::Value>,
+
+  /// Information about the run recorded in every exported data point. The
+  /// seed and the index of the node also determine the generators of the
+  /// chains.
+  pub metadata: ::export::Metadata,
+
+  /// Periodically checkpoint the samples and the values which haven't been
+  /// exported yet. Requires the codec passed to `run(..)`.
+  pub checkpoint: Option<CheckpointSettings>,
 }
 
-/// Runs the simulation in the infinite loop. Consumes `self`.
-/// The function `measure_fn` is called for each configuration sample. It is
+/// How often the thread exporting measures of multiple chains checks the stop
+/// criteria.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often the thread exporting measures of multiple chains checks whether
+/// the chains have taken the requested snapshots.
+const SNAPSHOT_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Interval between attempts to flush the remaining values when the simulation
+/// stops.
+const FINAL_FLUSH_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Runs the simulation until one of the stop criteria is met. Consumes `self`.
+/// Each chain calls `new_measure_fn` once to construct its own measurement
+/// function, which may keep mutable scratch state between samples. The
+/// measurement function is called for each configuration sample. It is
 /// supplied with the (immutable) sample, and a mutable reference to the
 /// collection of measures. The function should calculate the values of the
 /// physical quantities for the configuration sample and supply those to the
 /// accumulators contained in the collection of measures.
-pub fn run<S: Sample, F>(mut parameters: Parameters, measure_fn: F)
-  where F: Fn(&S, &mut Measures) {
+/// If `parameters.threads` is greater than 1, that many independent chains are
+/// run in separate threads. The measurement functions are constructed in the
+/// threads running the chains, so they don't have to be `Send`. The measures
+/// of the chains are merged before each export, so that the node still exports
+/// a single data point.
+/// Once the simulation stops, the remaining values are flushed, and the values
+/// accumulated over the whole run are returned.
+/// Checkpoints are only written if `codec` is given for serializing the
+/// samples.
+pub fn run<S, M, F>(mut parameters: Parameters, codec: Option<Codec<S>>,
+                    tracers: Vec<Tracer<S>>, hooks: &mut Hooks<S>,
+                    new_measure_fn: M) -> Results
+  where S: ParameterizedSample, M: Fn() -> F + Sync,
+        F: FnMut(&S, &mut Measures) {
   info!("Running ergothic simulation \"{}\".", &parameters.name);
-  // Prepare and thermalize a sample.
-  let mut sample = S::prepare();
-  sample.thermalize();
-  let mut last_export_timestamp = SystemTime::now();
-  let mut export_errors_in_row: usize = 0;
+  parameters.stop.check_targets(&parameters.measures);
+  if parameters.handle_signals {
+    ::stop::install_signal_handlers();
+  }
+  let thermalizer = Thermalizer {
+    params: sample_params::<S>(&mut parameters),
+    settings: parameters.equilibration.clone(),
+    tracers,
+    after_thermalize: hooks.after_thermalize.clone(),
+  };
+  let checkpointer = match (parameters.checkpoint.clone(), codec) {
+    (Some(settings), Some(codec)) => Some(Checkpointer::new(
+      settings, codec, parameters.metadata.params.clone())),
+    (Some(_), None) => panic!("Checkpoints are not supported by this \
+                               simulation. Call \
+                               Simulation::enable_checkpoints() to enable \
+                               them."),
+    (None, _) => None,
+  };
+  hooks.start(&parameters.metadata);
+  if parameters.threads > 1 {
+    run_threads(parameters, checkpointer, thermalizer, hooks, new_measure_fn)
+  } else {
+    run_single(parameters, checkpointer, thermalizer, hooks, new_measure_fn)
+  }
+}
+
+/// Deserializes the parameters of the sample given on the command line, and
+/// records them in the metadata of the exported data points. Panics if they
+/// can't be deserialized.
+fn sample_params<S: ParameterizedSample>(parameters: &mut Parameters)
+   -> S::Params {
+  let params: S::Params = match parameters.params.take() {
+    Some(value) => ::serde_json::from_value(value).unwrap_or_else(
+      |err| panic!("Failed to parse the parameters of the sample: {}", err)),
+    None => S::Params::default(),
+  };
+  parameters.metadata.params = ::serde_json::to_value(&params)
+    .expect("Failed to serialize the parameters of the sample.");
+  params
+}
+
+/// Restores the values which haven't been exported, the serialized samples and
+/// their generators from the checkpoint, if resuming. Returns a serialized
+/// sample with its generator for each of the chains, or `None` if the chain
+/// has to prepare a new sample.
+fn resume<S>(parameters: &mut Parameters,
+             checkpointer: &mut Option<Checkpointer<S>>)
+   -> Vec<Option<(::bson::Bson, Rng)>> {
+  let mut samples: Vec<Option<(::bson::Bson, Rng)>> =
+    (0..parameters.threads).map(|_| None).collect();
+  if let Some(checkpoint) = checkpointer.as_mut().and_then(|c| c.resume()) {
+    parameters.measures.merge(&checkpoint.measures);
+    if checkpoint.samples.len() > samples.len() {
+      warn!("Checkpoint contains {} samples, but only {} chains are running. \
+             Dropping the remaining samples.",
+            checkpoint.samples.len(), samples.len());
+    }
+    let chains = checkpoint.samples.into_iter().zip(checkpoint.rngs);
+    for (slot, chain) in samples.iter_mut().zip(chains) {
+      *slot = Some(chain);
+    }
+  }
+  samples
+}
+
+/// Derives the generator of the `chain`-th chain from the seed of the
+/// simulation.
+fn chain_rng(parameters: &Parameters, chain: usize) -> Rng {
+  ::rng::chain_rng(parameters.metadata.seed, parameters.metadata.node_index,
+                   chain as u64)
+}
+
+/// Runs a single chain in the current thread.
+fn run_single<S, M, F>(mut parameters: Parameters,
+                       mut checkpointer: Option<Checkpointer<S>>,
+                       thermalizer: Thermalizer<S>, hooks: &mut Hooks<S>,
+                       new_measure_fn: M) -> Results
+  where S: ParameterizedSample, M: Fn() -> F, F: FnMut(&S, &mut Measures) {
+  let start = Instant::now();
+  let codec = checkpointer.as_ref().map(|c| c.codec());
+  let restored = resume(&mut parameters, &mut checkpointer).remove(0);
+  let prepared = restored.is_none();
+  let (sample, rng, thermalization) = Chain::restore_or_prepare(
+    restored, codec, chain_rng(&parameters, 0), &thermalizer);
+  // Set after a panic has been caught or the thermalization has been
+  // interrupted, until the sample is prepared again.
+  let mut reprepare = prepared && ::stop::interrupted();
+  let thermalization = if reprepare { None } else { thermalization };
+  let mut chain =
+    Chain::new(sample, rng, thermalization, &mut parameters.measures);
+  let mut measure_fn = new_measure_fn();
+  let mut flusher = Flusher::new(&mut parameters, hooks);
+  let mut samples: usize = 0;
+  let mut samples_since_export: usize = 0;
+  let stop_reason = loop {
+    // The sample isn't prepared again while stopping.
+    if reprepare && ::stop::interrupted() {
+      break StopReason::Interrupted;
+    }
+    let refresh_mode = if reprepare {
+      Some(RefreshMode::Prepare)
+    } else if chain.refresh_due(&parameters.refresh) {
+      parameters.refresh.as_ref().map(|policy| policy.mode)
+    } else {
+      None
+    };
+    let refreshed = match refresh_mode {
+      Some(mode) => chain.try_refresh(mode, &thermalizer, parameters.on_panic),
+      None => Ok(None),
+    };
+    let stepped = match refreshed {
+      Ok(_) if refresh_mode.is_some() && ::stop::interrupted() => {
+        // The thermalization has been interrupted.
+        reprepare = true;
+        continue;
+      },
+      Ok(thermalization) => {
+        Chain::<S>::record_thermalization(&mut parameters.measures,
+                                          thermalization);
+        if reprepare {
+          // The state of the measurement function may be broken as well.
+          measure_fn = new_measure_fn();
+          reprepare = false;
+        }
+        let mutations = parameters.metadata.mutations_per_measurement;
+        match parameters.on_panic {
+          None => {
+            chain.step(mutations, &mut parameters.measures, &mut measure_fn);
+            Ok(())
+          },
+          Some(_) => chain.try_step(mutations, &mut parameters.measures,
+                                    &mut measure_fn),
+        }
+      },
+      Err(message) => Err(message),
+    };
+    match stepped {
+      Ok(()) => {
+        samples += 1;
+        samples_since_export += 1;
+      },
+      Err(message) => {
+        flusher.report_panic(&parameters, &message);
+        reprepare = true;
+        if parameters.on_panic == Some(PanicPolicy::Exit) {
+          break StopReason::Panicked;
+        }
+      },
+    }
+    if let Some(reason) =
+           parameters.stop.limit_reached(samples, start.elapsed()) {
+      break reason;
+    }
+    if flusher.time_until_due(&parameters) == Duration::from_secs(0) {
+      flusher.flush(&mut parameters, samples_since_export);
+      samples_since_export = 0;
+      if let Some(ref mut checkpointer) = checkpointer {
+        let progress = flusher.progress();
+        if checkpointer.is_due(progress.1) {
+          let snapshot = chain.snapshot(checkpointer.codec());
+          checkpointer.write(vec![snapshot],
+                             &flusher.unexported(&parameters), progress);
+        }
+      }
+      if parameters.stop.targets_reached(&flusher.accumulated(&parameters)) {
+        break StopReason::TargetUncertainty;
+      }
+    }
+  };
+  let results =
+    flusher.finish(&mut parameters, samples_since_export, stop_reason);
+  if let Some(ref mut checkpointer) = checkpointer {
+    // A sample broken by a panic or left unthermalized is left out, and
+    // prepared again on resume.
+    let snapshots = if reprepare {
+      Vec::new()
+    } else {
+      vec![chain.snapshot(checkpointer.codec())]
+    };
+    // The checkpointer isn't used after the final checkpoint.
+    checkpointer.write(snapshots, &parameters.measures, (0, 0));
+  }
+  results
+}
+
+/// Asks all running chains for snapshots of their samples, and waits until
+/// they respond. Chains preparing or refreshing their samples are not waited
+/// for, and are left out of the checkpoint. Returns the epoch of the
+/// requested snapshots, or `None` if the simulation is stopping or one of the
+/// chains has panicked in the meantime.
+fn request_snapshots(tallies: &[Mutex<Tally>], epoch: &AtomicUsize,
+                     stopping: &AtomicBool,
+                     handles: &[::std::thread::ScopedJoinHandle<()>])
+   -> Option<usize> {
+  let epoch = epoch.fetch_add(1, Ordering::Relaxed) + 1;
   loop {
-    // Mutate the sample. This draws a new configuration from the ergodic
-    // distribution.
-    sample.mutate();
-
-    // Measure and record the values of observables.
-    measure_fn(&sample, &mut parameters.measures);
-
-    if last_export_timestamp.elapsed().unwrap() >=
-      parameters.flush_interval {
-      last_export_timestamp = SystemTime::now();
-      // Export a new data point containing the accumulated expectations.
-      match parameters.exporter.export(&parameters.measures) {
-        Ok(()) => {
-          export_errors_in_row = 0;
-          // Exported a data point. Reset the accumulated expectations and
-          // continue the simulation.
-          parameters.measures.reset();
-        },
-        Err(::export::ExportError(ref err)) => {
-          export_errors_in_row += 1;
-          // Export failed. Reporting an error and keeping the accumulated
-          // expectations in hope of exporting them the next time.
-          error!("Failed to export measured values: {:?}", err);
-        },
+    let ready = tallies.iter().all(|tally| {
+      let tally = tally.lock().unwrap();
+      !tally.running ||
+        tally.snapshot.as_ref().map_or(false, |s| s.epoch == epoch)
+    });
+    if ready {
+      return Some(epoch);
+    }
+    if stopping.load(Ordering::Relaxed) || ::stop::interrupted() ||
+       handles.iter().any(|h| h.is_finished()) {
+      return None;
+    }
+    ::std::thread::sleep(SNAPSHOT_POLL_INTERVAL);
+  }
+}
+
+/// Takes the serialized samples of the snapshots of the given `epoch`, or of
+/// all snapshots if it is `None`. Snapshots of other epochs are dropped.
+/// Must be called after the measures of the snapshots have been collected.
+fn take_snapshots(tallies: &[Mutex<Tally>], epoch: Option<usize>)
+   -> Vec<(Result<::bson::Bson, String>, Rng)> {
+  tallies.iter()
+    .filter_map(|tally| tally.lock().unwrap().snapshot.take())
+    .filter(|snapshot| epoch.map_or(true, |epoch| snapshot.epoch == epoch))
+    .map(|snapshot| snapshot.chain)
+    .collect()
+}
+
+/// Sets the flag when dropped, including when the thread unwinds.
+struct StopOnDrop<'a>(&'a AtomicBool);
+
+impl<'a> Drop for StopOnDrop<'a> {
+  fn drop(&mut self) {
+    self.0.store(true, Ordering::Relaxed);
+  }
+}
+
+/// Runs `parameters.threads` independent chains in separate threads. The
+/// current thread merges their measures and exports them.
+fn run_threads<S, M, F>(mut parameters: Parameters,
+                        mut checkpointer: Option<Checkpointer<S>>,
+                        thermalizer: Thermalizer<S>, hooks: &mut Hooks<S>,
+                        new_measure_fn: M) -> Results
+  where S: ParameterizedSample, M: Fn() -> F + Sync,
+        F: FnMut(&S, &mut Measures) {
+  let start = Instant::now();
+  let tallies: Vec<Mutex<Tally>> = (0..parameters.threads)
+    .map(|_| Mutex::new(Tally {
+      measures: parameters.measures.clone(),
+      samples: 0,
+      running: false,
+      snapshot: None,
+      panics: Vec::new(),
+    }))
+    .collect();
+  let codec = checkpointer.as_ref().map(|c| c.codec());
+  let resumed = resume(&mut parameters, &mut checkpointer);
+  let mut flusher = Flusher::new(&mut parameters, hooks);
+  let stopping = AtomicBool::new(false);
+  let samples = AtomicUsize::new(0);
+  let snapshot_epoch = AtomicUsize::new(0);
+  let max_samples = parameters.stop.max_samples.unwrap_or(usize::MAX);
+  let refresh = parameters.refresh.clone();
+  let on_panic = parameters.on_panic;
+  let panicked = AtomicBool::new(false);
+  ::std::thread::scope(|scope| {
+    // The scope waits for the chains before returning, even when the current
+    // thread unwinds. Stop them on every exit path, so that a panic here
+    // (such as one propagated from a chain) doesn't hang the simulation.
+    let _stop_chains = StopOnDrop(&stopping);
+    let mut handles = Vec::new();
+    for (i, (tally, restored)) in tallies.iter().zip(resumed).enumerate() {
+      let new_measure_fn = &new_measure_fn;
+      let thermalizer = &thermalizer;
+      let stopping = &stopping;
+      let samples = &samples;
+      let snapshot_epoch = &snapshot_epoch;
+      let rng = chain_rng(&parameters, i);
+      let mutations = parameters.metadata.mutations_per_measurement;
+      let refresh = &refresh;
+      let panicked = &panicked;
+      let handle = ::std::thread::Builder::new()
+        .name(format!("ergothic-chain-{}", i))
+        .spawn_scoped(scope, move || {
+          // Thermalization may take long, so it happens without holding the
+          // lock on the tally.
+          let prepared = restored.is_none();
+          let (sample, rng, thermalization) =
+            Chain::restore_or_prepare(restored, codec, rng, thermalizer);
+          // Set after a panic has been caught or the thermalization has been
+          // interrupted, until the sample is prepared again.
+          let mut reprepare = prepared && ::stop::interrupted();
+          let thermalization = if reprepare { None } else { thermalization };
+          let mut chain = {
+            let mut tally = tally.lock().unwrap();
+            tally.running = !reprepare;
+            Chain::<S>::new(sample, rng, thermalization, &mut tally.measures)
+          };
+          let mut measure_fn = new_measure_fn();
+          let mut epoch = 0;
+          while !stopping.load(Ordering::Relaxed) {
+            // The sample isn't prepared again while stopping.
+            if reprepare && ::stop::interrupted() {
+              break;
+            }
+            // Claim the next sample, so that all chains together draw exactly
+            // `max_samples` samples.
+            if samples.fetch_add(1, Ordering::Relaxed) >= max_samples {
+              break;
+            }
+            let refresh_mode = if reprepare {
+              Some(RefreshMode::Prepare)
+            } else if chain.refresh_due(refresh) {
+              refresh.as_ref().map(|policy| policy.mode)
+            } else {
+              None
+            };
+            // The sample is refreshed without holding the lock. Refreshing
+            // may take long, so the chain isn't asked for snapshots meanwhile.
+            let refreshed = match refresh_mode {
+              Some(mode) => {
+                tally.lock().unwrap().running = false;
+                chain.try_refresh(mode, thermalizer, on_panic)
+              },
+              None => Ok(None),
+            };
+            let mut tally = tally.lock().unwrap();
+            let stepped = match refreshed {
+              Ok(_) if refresh_mode.is_some() && ::stop::interrupted() => {
+                // The thermalization has been interrupted.
+                reprepare = true;
+                continue;
+              },
+              Ok(thermalization) => {
+                tally.running = true;
+                Chain::<S>::record_thermalization(&mut tally.measures,
+                                                  thermalization);
+                if reprepare {
+                  // The state of the measurement function may be broken as
+                  // well.
+                  measure_fn = new_measure_fn();
+                  reprepare = false;
+                }
+                match on_panic {
+                  None => {
+                    chain.step(mutations, &mut tally.measures,
+                               &mut measure_fn);
+                    Ok(())
+                  },
+                  Some(_) => chain.try_step(mutations, &mut tally.measures,
+                                            &mut measure_fn),
+                }
+              },
+              Err(message) => Err(message),
+            };
+            if let Err(message) = stepped {
+              tally.panics.push(message);
+              reprepare = true;
+              if on_panic == Some(PanicPolicy::Exit) {
+                panicked.store(true, Ordering::Relaxed);
+                break;
+              }
+              continue;
+            }
+            tally.samples += 1;
+            if let Some(codec) = codec {
+              let requested = snapshot_epoch.load(Ordering::Relaxed);
+              if requested != epoch {
+                epoch = requested;
+                tally.snapshot(epoch, codec, &chain);
+              }
+            }
+          }
+          // The final checkpoint is written after the chains stop. A sample
+          // broken by a panic or left unthermalized is left out, and
+          // prepared again on resume.
+          if let Some(codec) = codec {
+            let mut tally = tally.lock().unwrap();
+            if reprepare {
+              tally.drop_snapshot();
+            } else {
+              tally.snapshot(epoch, codec, &chain);
+            }
+          }
+        })
+        .expect("Failed to spawn a simulation thread.");
+      handles.push(handle);
+    }
+    info!("Running {} chains in separate threads.", handles.len());
+
+    // Collects the measures of the chains. Returns the number of samples drawn
+    // since the last export and the messages of the panics caught meanwhile.
+    let collect = |measures: &mut Measures| {
+      let mut samples_since_export: usize = 0;
+      let mut panics = Vec::new();
+      for tally in tallies.iter() {
+        let mut tally = tally.lock().unwrap();
+        panics.append(&mut tally.panics);
+        measures.merge(&tally.measures);
+        tally.measures.reset();
+        if let Some(ref mut snapshot) = tally.snapshot {
+          measures.merge(&snapshot.measures);
+          snapshot.measures.reset();
+        }
+        samples_since_export += tally.samples;
+        tally.samples = 0;
       }
-      if let Some(ref max_export_errors_in_row) =
-             parameters.max_export_errors_in_row {
-        if export_errors_in_row >= *max_export_errors_in_row {
-          panic!("Reached a maximum of {} export errors in row.",
-                 *max_export_errors_in_row);
+      (samples_since_export, panics)
+    };
+
+    let stop_reason = loop {
+      ::std::thread::sleep(::std::cmp::min(
+        flusher.time_until_due(&parameters), STOP_POLL_INTERVAL));
+      // Chains only return when stopping or when exiting on a caught panic,
+      // so a thread which finished otherwise has panicked.
+      if let Some(pos) = handles.iter().position(|h| h.is_finished()) {
+        if let Err(err) = handles.remove(pos).join() {
+          ::std::panic::resume_unwind(err);
         }
       }
+      if panicked.load(Ordering::Relaxed) {
+        break StopReason::Panicked;
+      }
+      if let Some(reason) = parameters.stop.limit_reached(
+             samples.load(Ordering::Relaxed), start.elapsed()) {
+        break reason;
+      }
+      if flusher.time_until_due(&parameters) == Duration::from_secs(0) {
+        // Snapshots are taken before collecting the measures, so that the
+        // checkpoint contains exactly the values measured on the samples
+        // preceding the snapshots.
+        let delivered = flusher.progress().1;
+        let checkpoint_epoch = if checkpointer.as_ref()
+                                    .map_or(false, |c| c.is_due(delivered)) {
+          request_snapshots(&tallies, &snapshot_epoch, &stopping, &handles)
+        } else {
+          None
+        };
+        let (samples_since_export, panics) =
+          collect(&mut parameters.measures);
+        for message in panics {
+          flusher.report_panic(&parameters, &message);
+        }
+        flusher.flush(&mut parameters, samples_since_export);
+        if let (Some(checkpointer), Some(epoch)) =
+               (checkpointer.as_mut(), checkpoint_epoch) {
+          let progress = flusher.progress();
+          checkpointer.write(take_snapshots(&tallies, Some(epoch)),
+                             &flusher.unexported(&parameters), progress);
+        }
+        if parameters.stop.targets_reached(
+               &flusher.accumulated(&parameters)) {
+          break StopReason::TargetUncertainty;
+        }
+      }
+    };
+
+    stopping.store(true, Ordering::Relaxed);
+    for handle in handles {
+      if let Err(err) = handle.join() {
+        ::std::panic::resume_unwind(err);
+      }
+    }
+    let (samples_since_export, panics) = collect(&mut parameters.measures);
+    for message in panics {
+      flusher.report_panic(&parameters, &message);
+    }
+    let results =
+      flusher.finish(&mut parameters, samples_since_export, stop_reason);
+    if let Some(ref mut checkpointer) = checkpointer {
+      // The checkpointer isn't used after the final checkpoint.
+      checkpointer.write(take_snapshots(&tallies, None),
+                         &parameters.measures, (0, 0));
+    }
+    results
+  })
+}
+
+/// Runs replicas of the sample at each of the `couplings` in the current
+/// thread, attempting to swap neighbouring replicas after every sample. Values
+/// measured at different couplings are exported separately, the name of every
+/// measure being given the suffix `@<coupling>`. Drawing a sample means
+/// drawing it at every coupling. Checkpoints, multiple threads, refreshing the
+/// samples and catching panics are not supported.
+pub fn run_tempering<S, M, F>(mut parameters: Parameters,
+                              couplings: Vec<f64>, tracers: Vec<Tracer<S>>,
+                              hooks: &mut Hooks<S>, new_measure_fn: M)
+   -> Results
+  where S: TemperedSample, M: Fn() -> F, F: FnMut(&S, &mut Measures) {
+  info!("Running ergothic simulation \"{}\" with parallel tempering at {} \
+         couplings.", &parameters.name, couplings.len());
+  if parameters.checkpoint.is_some() {
+    panic!("Checkpoints are not supported with parallel tempering.");
+  }
+  if parameters.threads > 1 {
+    panic!("Parallel tempering runs in a single thread.");
+  }
+  if parameters.refresh.is_some() {
+    panic!("Refreshing the samples is not supported with parallel tempering.");
+  }
+  if parameters.on_panic.is_some() {
+    panic!("Catching panics is not supported with parallel tempering.");
+  }
+  if parameters.handle_signals {
+    ::stop::install_signal_handlers();
+  }
+  let start = Instant::now();
+  let thermalizer = Thermalizer {
+    params: sample_params::<S>(&mut parameters),
+    settings: parameters.equilibration.clone(),
+    tracers,
+    after_thermalize: hooks.after_thermalize.clone(),
+  };
+  hooks.start(&parameters.metadata);
+  // The replicas record the measures, which are only collected in
+  // `parameters.measures` with the suffixes before each export.
+  let template =
+    ::std::mem::replace(&mut parameters.measures, Measures::new_empty());
+  let mut exported = Measures::new_empty();
+  for coupling in couplings.iter() {
+    exported.merge_suffixed(&template, &format!("@{}", coupling));
+  }
+  parameters.stop.check_targets(&exported);
+  let rngs = (0..couplings.len()).map(|k| chain_rng(&parameters, k)).collect();
+  let swap_rng = chain_rng(&parameters, couplings.len());
+  let mut ensemble =
+    Ensemble::new(&couplings, &template, rngs, swap_rng, &thermalizer);
+  // The replicas run in the same thread, so they share the measurement
+  // function.
+  let mut measure_fn = new_measure_fn();
+  let mut flusher = Flusher::new(&mut parameters, hooks);
+  let mut samples: usize = 0;
+  let mut samples_since_export: usize = 0;
+  let stop_reason = loop {
+    // The replicas are left unthermalized if the process is interrupted while
+    // preparing them.
+    if samples == 0 && ::stop::interrupted() {
+      break StopReason::Interrupted;
+    }
+    ensemble.step(parameters.metadata.mutations_per_measurement,
+                  &mut measure_fn);
+    samples += 1;
+    samples_since_export += 1;
+    if let Some(reason) =
+           parameters.stop.limit_reached(samples, start.elapsed()) {
+      break reason;
+    }
+    if flusher.time_until_due(&parameters) == Duration::from_secs(0) {
+      ensemble.collect(&mut parameters.measures);
+      flusher.flush(&mut parameters, samples_since_export);
+      samples_since_export = 0;
+      if parameters.stop.targets_reached(&flusher.accumulated(&parameters)) {
+        break StopReason::TargetUncertainty;
+      }
+    }
+  };
+  ensemble.collect(&mut parameters.measures);
+  flusher.finish(&mut parameters, samples_since_export, stop_reason)
+}
+
+#[cfg(test)]
+mod tests {
+  use ::builder::SimulationBuilder;
+  use ::export::ExportError;
+  use ::export::Exporter;
+  use ::export::Metadata;
+  use ::measure::Measures;
+  use ::panics::PanicPolicy;
+  use ::startup::EXIT_CODE_PANICKED;
+  use ::startup::exit_status;
+  use ::std::cell::Cell;
+  use ::std::sync::Arc;
+  use ::std::sync::Mutex;
+  use ::std::sync::atomic::AtomicBool;
+  use ::std::sync::atomic::Ordering;
+  use ::std::sync::mpsc;
+  use ::std::time::Duration;
+  use ::stop::Results;
+  use ::stop::StopReason;
+
+  /// Discards the exported values.
+  struct NullExporter;
+
+  impl Exporter for NullExporter {
+    fn export(&mut self, _metadata: &Metadata, _measures: &Measures)
+       -> Result<(), ExportError> {
+      Ok(())
     }
   }
+
+  /// Set once a `PanicOnce` sample has panicked.
+  static PANICKED: AtomicBool = AtomicBool::new(false);
+
+  /// A sample whose first mutation in the whole process panics, so that only
+  /// one of the chains dies.
+  struct PanicOnce;
+
+  impl ::simulation::Sample for PanicOnce {
+    fn prepare() -> PanicOnce {
+      PanicOnce
+    }
+
+    fn mutate(&mut self) {
+      if !PANICKED.swap(true, Ordering::SeqCst) {
+        panic!("The chain has panicked.");
+      }
+    }
+  }
+
+  #[test]
+  fn chain_panic_stops_other_chains() {
+    let (sender, receiver) = mpsc::channel();
+    ::std::thread::spawn(move || {
+      let result = ::std::panic::catch_unwind(|| {
+        let mut sim = ::Simulation::new("Panicking chain");
+        sim.configure(SimulationBuilder::new()
+                        .exporter(NullExporter)
+                        .threads(2));
+        sim.run(|_: &PanicOnce, _| {});
+      });
+      sender.send(result.is_err()).unwrap();
+    });
+    let panicked = receiver.recv_timeout(Duration::from_secs(30))
+      .expect("The simulation hung after a chain panicked.");
+    assert!(panicked, "The panic of the chain wasn't propagated.");
+  }
+
+  /// Keeps the messages of the reported panics.
+  struct PanicRecorder(Arc<Mutex<Vec<String>>>);
+
+  impl Exporter for PanicRecorder {
+    fn export(&mut self, _metadata: &Metadata, _measures: &Measures)
+       -> Result<(), ExportError> {
+      Ok(())
+    }
+
+    fn report_panic(&mut self, _metadata: &Metadata, message: &str)
+       -> Result<(), ExportError> {
+      self.0.lock().unwrap().push(message.to_string());
+      Ok(())
+    }
+  }
+
+  thread_local! {
+    /// Number of `Flaky` samples prepared in the current thread.
+    static PREPARED: Cell<usize> = const { Cell::new(0) };
+
+    /// Number of `Flaky` samples measured in the current thread.
+    static MEASURED: Cell<usize> = const { Cell::new(0) };
+  }
+
+  /// Increments the `counter` and gives its new value.
+  fn count(counter: &'static ::std::thread::LocalKey<Cell<usize>>) -> usize {
+    counter.with(|counter| {
+      counter.set(counter.get() + 1);
+      counter.get()
+    })
+  }
+
+  /// A sample whose second preparation panics.
+  struct Flaky;
+
+  impl ::simulation::Sample for Flaky {
+    fn prepare() -> Flaky {
+      if count(&PREPARED) == 2 {
+        panic!("Failed to prepare the sample.");
+      }
+      Flaky
+    }
+
+    fn mutate(&mut self) {}
+  }
+
+  /// Runs a single chain of `Flaky` samples with the panic `policy`. The
+  /// measurement function records the measure `x` and then panics on the
+  /// third sample. Returns the results and the reported panics.
+  fn run_flaky(policy: PanicPolicy) -> (Results, Vec<String>) {
+    let panics = Arc::new(Mutex::new(Vec::new()));
+    let mut sim = ::Simulation::new("Flaky");
+    let x = sim.add_measure("x");
+    sim.configure(SimulationBuilder::new()
+                    .exporter(PanicRecorder(panics.clone()))
+                    .on_panic(policy)
+                    .max_samples(10));
+    let results = sim.run(move |_: &Flaky, ms| {
+      ms.accumulate(x, 1.0);
+      if count(&MEASURED) == 3 {
+        panic!("Failed to measure the sample.");
+      }
+    });
+    let panics = panics.lock().unwrap().clone();
+    (results, panics)
+  }
+
+  #[test]
+  fn reprepare_discards_offending_sample() {
+    let (results, panics) = run_flaky(PanicPolicy::Reprepare);
+    assert_eq!(results.stop_reason, StopReason::MaxSamples);
+    assert_eq!(results.get("x").unwrap().count, 10.0);
+    // The panic while preparing the sample again is caught as well.
+    assert_eq!(panics, vec!["Failed to measure the sample.",
+                            "Failed to prepare the sample."]);
+    assert_eq!(exit_status(&results), None);
+  }
+
+  #[test]
+  fn exit_discards_offending_sample_and_stops() {
+    let (results, panics) = run_flaky(PanicPolicy::Exit);
+    assert_eq!(results.stop_reason, StopReason::Panicked);
+    assert!(results.flushed);
+    assert_eq!(results.get("x").unwrap().count, 2.0);
+    assert_eq!(panics, vec!["Failed to measure the sample."]);
+    assert_eq!(exit_status(&results).map(|(code, _)| code),
+               Some(EXIT_CODE_PANICKED));
+  }
 }
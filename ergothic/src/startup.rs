@@ -1,10 +1,24 @@
+use ::builder::SimulationBuilder;
+use ::checkpoint::CheckpointSettings;
+use ::equilibrate::EquilibrationSettings;
 use ::export::Exporter;
 use ::measure::MeasureRegistry;
 use ::measure::Measures;
+use ::panics::PanicPolicy;
+use ::refresh::RefreshMode;
+use ::refresh::RefreshPolicy;
 use ::simulation::Parameters;
+use ::stop::Results;
+use ::stop::StopReason;
+use ::stop::StopCriteria;
+use ::stop::Uncertainty;
+use ::stop::UncertaintyTarget;
+use ::std::time::Duration;
+use ::std::time::Instant;
 use ::structopt::StructOpt;
+use ::sweep::Sweep;
 
-#[derive(Debug, StructOpt)]
+#[derive(Clone, Debug, StructOpt)]
 #[structopt(name="ergothic simulation",
   about="A distributed statistical simulation using ergothic library.")]
 pub struct CmdArgs {
@@ -42,18 +56,243 @@ pub struct CmdArgs {
   #[structopt(long="flush_interval_randomization", default_value="0.5")]
   pub flush_interval_randomization: f64,
 
+  /// Maximum number of data points waiting to be exported. Data points are
+  /// exported in a background thread, so that slow exports don't stall the
+  /// simulation. Values flushed while the queue is full are merged into the
+  /// last waiting data point.
+  /// Example: --export_queue_len 4
+  #[structopt(long="export_queue_len", default_value="2")]
+  pub export_queue_len: usize,
+
   /// Simulation will panic after receiving this many export errors in a row.
   /// Default value is infinity.
   #[structopt(long="max_errors_in_row")]
   pub max_export_errors_in_row: Option<usize>,
+
+  /// Number of independent chains to run in separate threads. Each chain
+  /// prepares and thermalizes its own sample. Measures from all chains are
+  /// merged before exporting, so that the node exports a single data point.
+  /// Example: --threads 8
+  #[structopt(long="threads", default_value="1")]
+  pub threads: usize,
+
+  /// Stop the simulation after drawing this many samples (over all threads).
+  /// Default value is infinity.
+  /// Example: --max_samples 1000000
+  #[structopt(long="max_samples")]
+  pub max_samples: Option<usize>,
+
+  /// Stop the simulation after running for this many seconds.
+  /// Default value is infinity.
+  /// Example: --max_time_secs 3600 (stop after an hour).
+  #[structopt(long="max_time_secs")]
+  pub max_time_secs: Option<u64>,
+
+  /// Stop the simulation once the measure reaches the given absolute
+  /// uncertainty. Can be repeated, in which case the simulation stops once all
+  /// of the targets are reached.
+  /// Example: --target_uncertainty "Mean X=0.001"
+  #[structopt(long="target_uncertainty")]
+  pub target_uncertainty: Vec<String>,
+
+  /// Stop the simulation once the measure reaches the given relative
+  /// uncertainty. Can be repeated, in which case the simulation stops once all
+  /// of the targets are reached.
+  /// Example: --target_relative_uncertainty "Mean X=0.01"
+  #[structopt(long="target_relative_uncertainty")]
+  pub target_relative_uncertainty: Vec<String>,
+
+  /// On SIGINT or SIGTERM, the simulation finishes the current step and
+  /// flushes the remaining values. Failed flushes are retried for this many
+  /// seconds. Should be shorter than the grace period given by the cluster
+  /// scheduler before it kills the process.
+  /// Example: --shutdown_grace_secs 20
+  #[structopt(long="shutdown_grace_secs", default_value="20")]
+  pub shutdown_grace_secs: u64,
+
+  /// Path to the checkpoint file. The samples and the values which haven't
+  /// been exported yet are periodically written to this file. Requires the
+  /// simulation to enable checkpoints. Child arguments:
+  /// [--checkpoint_interval_secs, --resume].
+  /// Example: --checkpoint /scratch/my_simulation.ckpt
+  #[structopt(long="checkpoint")]
+  pub checkpoint: Option<String>,
+
+  /// Minimal interval between subsequent checkpoints in seconds. Parent
+  /// argument: --checkpoint.
+  /// Example: --checkpoint_interval_secs 1800 (every half an hour).
+  #[structopt(long="checkpoint_interval_secs", default_value="600")]
+  pub checkpoint_interval_secs: u64,
+
+  /// Resume from the checkpoint file instead of preparing and thermalizing new
+  /// samples. Starts from scratch if the file doesn't exist yet. Parent
+  /// argument: --checkpoint.
+  #[structopt(long="resume")]
+  pub resume: bool,
+
+  /// Seed of the random number generators handed to the samples. Runs with
+  /// the same seed and a single thread are reproducible. Drawn at random by
+  /// default. The seed is recorded in every exported data point. Must be less
+  /// than 2^63.
+  /// Example: --seed 42
+  #[structopt(long="seed")]
+  pub seed: Option<u64>,
+
+  /// Index of the node. Nodes running with the same seed must have different
+  /// indices, otherwise they will produce identical data points. Should be
+  /// less than 2^63.
+  /// Example: --node_index 17
+  #[structopt(long="node_index", default_value="0")]
+  pub node_index: u64,
+
+  /// Number of mutations applied to the sample between subsequent
+  /// measurements. Larger values reduce the autocorrelation of the measured
+  /// values at the cost of more mutations per sample. Overrides the value set
+  /// with `Simulation::set_mutations_per_measurement(..)`, which is 1 by
+  /// default.
+  /// Example: --mutations_per_measurement 20
+  #[structopt(long="mutations_per_measurement")]
+  pub mutations_per_measurement: Option<usize>,
+
+  /// Thermalize new samples adaptively instead of calling
+  /// `Sample::thermalize`: mutate the sample until the tracked observables
+  /// (the action by default) become stationary according to the MSER rule.
+  /// Child arguments: [--min_thermalization, --max_thermalization].
+  #[structopt(long="adaptive_thermalization")]
+  pub adaptive_thermalization: bool,
+
+  /// Check the tracked observables for stationarity after this many mutations
+  /// for the first time, and each time the number of mutations doubles.
+  /// Parent argument: --adaptive_thermalization.
+  /// Example: --min_thermalization 100
+  #[structopt(long="min_thermalization", default_value="100")]
+  pub min_thermalization: usize,
+
+  /// Give up on the adaptive thermalization after this many mutations.
+  /// Parent argument: --adaptive_thermalization.
+  /// Example: --max_thermalization 1000000
+  #[structopt(long="max_thermalization", default_value="1000000")]
+  pub max_thermalization: usize,
+
+  /// Refresh the sample of each chain after drawing this many samples.
+  /// Child argument: --refresh_mode.
+  /// Example: --refresh_every_samples 100000
+  #[structopt(long="refresh_every_samples")]
+  pub refresh_every_samples: Option<usize>,
+
+  /// Refresh the sample of each chain after running for this many seconds.
+  /// Child argument: --refresh_mode.
+  /// Example: --refresh_every_secs 7200 (every two hours).
+  #[structopt(long="refresh_every_secs")]
+  pub refresh_every_secs: Option<u64>,
+
+  /// What happens to the sample on refresh: "prepare" discards it and
+  /// prepares and thermalizes a new one, "thermalize" thermalizes the current
+  /// sample again. Parent arguments: [--refresh_every_samples,
+  /// --refresh_every_secs].
+  /// Example: --refresh_mode thermalize
+  #[structopt(long="refresh_mode", default_value="prepare")]
+  pub refresh_mode: RefreshMode,
+
+  /// Catch panics in the sample and in the measurement function, discard the
+  /// offending sample and report the panic to the exporter. Then "reprepare"
+  /// prepares and thermalizes a new sample and continues, while "exit" flushes
+  /// the accumulated values and exits with status 4. By default, panics are
+  /// not caught.
+  /// Example: --on_panic reprepare
+  #[structopt(long="on_panic")]
+  pub on_panic: Option<PanicPolicy>,
+
+  /// Parameter of the sample, given as <name>=<value>. The value is parsed as
+  /// JSON, or taken as a string if it isn't valid JSON. Can be repeated.
+  /// Overrides the parameters read from --params_file.
+  /// Example: --param lattice_spacing=0.25
+  #[structopt(long="param")]
+  pub param: Vec<String>,
+
+  /// Path to a JSON file with an object containing the parameters of the
+  /// sample.
+  /// Example: --params_file oscillator.json
+  #[structopt(long="params_file")]
+  pub params_file: Option<String>,
+
+  /// Sweep a parameter of the sample over a range or a list of values, given
+  /// as <name>=<start>:<stop>:<step> or <name>=<value>,<value>,... Can be
+  /// repeated, in which case all combinations of the values are swept. Each
+  /// node runs at the point chosen by its --node_index, unless
+  /// --sweep_interval_secs is given. Overrides --param.
+  /// Example: --sweep beta=5.6:6.2:0.05
+  #[structopt(long="sweep")]
+  pub sweep: Vec<String>,
+
+  /// Cycle through the points of the sweep, spending this many seconds at each
+  /// of them. Nodes start at the points chosen by their --node_index. Only
+  /// --max_time_secs and termination signals stop the simulation. Parent
+  /// argument: --sweep.
+  /// Example: --sweep_interval_secs 3600 (switch points every hour).
+  #[structopt(long="sweep_interval_secs")]
+  pub sweep_interval_secs: Option<u64>,
+}
+
+/// Parses an uncertainty target of the form `<measure name>=<uncertainty>`.
+fn parse_target(spec: &str, make: fn(f64) -> Uncertainty)
+   -> UncertaintyTarget {
+  let pos = spec.rfind('=').unwrap_or_else(
+    || panic!("Expected <measure name>=<uncertainty>, found '{}'.", spec));
+  let uncertainty = spec[pos + 1..].trim().parse::<f64>().unwrap_or_else(
+    |_| panic!("Failed to parse the uncertainty in '{}'.", spec));
+  UncertaintyTarget {
+    measure: spec[..pos].to_string(),
+    uncertainty: make(uncertainty),
+  }
+}
+
+/// Reads the parameters of the sample from `--params_file` and `--param`.
+/// Returns `None` if neither is given. Panics on errors.
+fn parse_params(params_file: Option<String>, param: &[String])
+   -> Option<::serde_json::Value> {
+  if params_file.is_none() && param.is_empty() {
+    return None;
+  }
+  let mut params = match params_file {
+    Some(path) => {
+      let file = ::std::fs::File::open(&path).unwrap_or_else(
+        |err| panic!("Failed to open params file {}: {}", path, err));
+      match ::serde_json::from_reader(file) {
+        Ok(::serde_json::Value::Object(params)) => params,
+        Ok(_) => panic!("Params file {} should contain a JSON object.", path),
+        Err(err) => panic!("Failed to parse params file {}: {}", path, err),
+      }
+    },
+    None => ::serde_json::Map::new(),
+  };
+  for spec in param.iter() {
+    let pos = spec.find('=').unwrap_or_else(
+      || panic!("Expected <name>=<value>, found '{}'.", spec));
+    let value = &spec[pos + 1..];
+    let value = ::serde_json::from_str(value)
+      .unwrap_or_else(|_| ::serde_json::Value::String(value.to_string()));
+    params.insert(spec[..pos].to_string(), value);
+  }
+  Some(::serde_json::Value::Object(params))
 }
 
+/// Process exit status used when the simulation stops without exporting all of
+/// the measured values.
+pub const EXIT_CODE_NOT_FLUSHED: i32 = 3;
+
+/// Process exit status used when the simulation stops on a caught panic.
+pub const EXIT_CODE_PANICKED: i32 = 4;
+
 /// Parses the command line arguments and produces simulation parameters.
-pub fn construct_parameters(name: String, measures: Measures, mut args: CmdArgs)
-       -> Parameters {
+/// `mutations_per_measurement` is the default used unless overridden on the
+/// command line.
+pub fn construct_parameters(name: String, measures: Measures,
+                            mutations_per_measurement: usize,
+                            mut args: CmdArgs) -> Parameters {
   let mut rng = ::rand::thread_rng();
   use ::rand::distributions::Distribution;
-  let exporter: Box<dyn Exporter>;
+  let exporter: Box<dyn Exporter + Send>;
   if args.production_mode {
     if cfg!(debug_assertions) {
       panic!("Please build an optimized binary.");
@@ -108,25 +347,260 @@ pub fn construct_parameters(name: String, measures: Measures, mut args: CmdArgs)
 
   let max_export_errors_in_row = args.max_export_errors_in_row;
 
+  if args.export_queue_len == 0 {
+    panic!("Argument --export_queue_len should be positive.");
+  }
+
+  if args.threads == 0 {
+    panic!("Argument --threads should be positive.");
+  }
+  let threads = args.threads;
+
+  let mut targets = Vec::new();
+  for spec in args.target_uncertainty.iter() {
+    targets.push(parse_target(spec, Uncertainty::Absolute));
+  }
+  for spec in args.target_relative_uncertainty.iter() {
+    targets.push(parse_target(spec, Uncertainty::Relative));
+  }
+  let stop = StopCriteria {
+    max_samples: args.max_samples,
+    max_duration: args.max_time_secs.map(::std::time::Duration::from_secs),
+    targets,
+  };
+
+  if args.resume && args.checkpoint.is_none() {
+    panic!("Parent argument --checkpoint is required by --resume.");
+  }
+  let checkpoint_interval =
+    ::std::time::Duration::from_secs(args.checkpoint_interval_secs);
+  let resume = args.resume;
+  let checkpoint = args.checkpoint.map(|path| CheckpointSettings {
+    path: path.into(),
+    interval: checkpoint_interval,
+    resume,
+  });
+
+  let equilibration = if args.adaptive_thermalization {
+    Some(EquilibrationSettings {
+      min_mutations: args.min_thermalization,
+      max_mutations: args.max_thermalization,
+    })
+  } else {
+    None
+  };
+
+  let refresh = if args.refresh_every_samples.is_some() ||
+                   args.refresh_every_secs.is_some() {
+    if args.refresh_every_samples == Some(0) {
+      panic!("Argument --refresh_every_samples should be positive.");
+    }
+    Some(RefreshPolicy {
+      mode: args.refresh_mode,
+      every_samples: args.refresh_every_samples,
+      every_duration:
+        args.refresh_every_secs.map(::std::time::Duration::from_secs),
+    })
+  } else {
+    None
+  };
+
+  let seed = match args.seed {
+    Some(seed) if seed > ::rng::MAX_SEED =>
+      panic!("Argument --seed should be less than 2^63."),
+    Some(seed) => seed,
+    None => ::rng::random_seed(),
+  };
+  let mutations_per_measurement =
+    args.mutations_per_measurement.unwrap_or(mutations_per_measurement);
+  if mutations_per_measurement == 0 {
+    panic!("Argument --mutations_per_measurement should be positive.");
+  }
+  if args.node_index > ::export::MAX_NODE_INDEX {
+    panic!("Argument --node_index should be less than 2^63.");
+  }
+  let metadata = ::export::Metadata {
+    seed,
+    node_index: args.node_index,
+    mutations_per_measurement,
+    params: ::serde_json::Value::Null,
+    sweep_point: None,
+  };
+  let params = parse_params(args.params_file, &args.param);
+
   Parameters {
     name,
     measures,
-    exporter,
+    exporter: Some(exporter),
+    export_queue_len: args.export_queue_len,
     flush_interval,
     max_export_errors_in_row,
+    threads,
+    stop,
+    handle_signals: true,
+    shutdown_grace_period:
+      ::std::time::Duration::from_secs(args.shutdown_grace_secs),
+    equilibration,
+    refresh,
+    on_panic: args.on_panic,
+    params,
+    metadata,
+    checkpoint,
+  }
+}
+
+/// Runs the simulation `engine` with the parameters given by `config`, or
+/// with the ones parsed from the command line if it is `None`.
+pub fn start<E>(name: &str, reg: MeasureRegistry,
+                mutations_per_measurement: usize,
+                config: Option<SimulationBuilder>, engine: E) -> Results
+  where E: FnMut(Parameters) -> Results {
+  match config {
+    Some(builder) => ::builder::launch(builder, name, reg,
+                                       mutations_per_measurement, engine),
+    None => launch(name, reg, mutations_per_measurement, engine),
   }
 }
 
-pub fn run_simulation<S, F>(name: &str, reg: MeasureRegistry, measure_fn: F)
-  where S: ::simulation::Sample,
-        F: Fn(&S, &mut Measures) {
+/// Cycles through the points of the `sweep`, running the simulation `engine`
+/// at each of them for `sweep.interval`. Every run gets its own seed derived
+/// from the seed of the process. Returns the results of the last run.
+fn run_round_robin<E>(name: &str, measures: Measures,
+                      mutations_per_measurement: usize, mut args: CmdArgs,
+                      sweep: &Sweep, engine: &mut E) -> Results
+  where E: FnMut(Parameters) -> Results {
+  if args.checkpoint.is_some() {
+    panic!("Checkpoints are not supported with --sweep_interval_secs.");
+  }
+  if args.max_samples.is_some() || !args.target_uncertainty.is_empty() ||
+     !args.target_relative_uncertainty.is_empty() {
+    panic!("Only --max_time_secs can stop a simulation cycling through the \
+            points of a sweep.");
+  }
+  let interval = sweep.interval.unwrap();
+  let max_duration = args.max_time_secs.map(Duration::from_secs);
+  let seed = match args.seed {
+    Some(seed) if seed > ::rng::MAX_SEED =>
+      panic!("Argument --seed should be less than 2^63."),
+    Some(seed) => seed,
+    None => ::rng::random_seed(),
+  };
+  args.seed = Some(seed);
+  let node_index = args.node_index;
+  let start = Instant::now();
+  let mut flushed = true;
+  let mut run: u64 = 0;
+  loop {
+    let mut parameters = construct_parameters(
+      name.to_string(), measures.clone(), mutations_per_measurement,
+      args.clone());
+    // The deadline may pass while the next run is being set up.
+    parameters.stop.max_duration = Some(max_duration.map_or(interval,
+      |max| ::std::cmp::min(interval, max.saturating_sub(start.elapsed()))));
+    parameters.metadata.seed = ::rng::derive_seed(seed, run);
+    sweep.apply(node_index.wrapping_add(run), &mut parameters);
+    let results = engine(parameters);
+    flushed &= results.flushed;
+    let finished = results.stop_reason != StopReason::MaxDuration ||
+      max_duration.map_or(false, |max| start.elapsed() >= max);
+    if finished {
+      return Results {
+        flushed,
+        ..results
+      };
+    }
+    run += 1;
+  }
+}
+
+/// Parses the command line arguments and runs the simulation `engine` with
+/// the resulting parameters, once or at each point of a sweep. Exits the
+/// process if the measured values haven't been flushed.
+pub fn launch<E>(name: &str, reg: MeasureRegistry,
+                 mutations_per_measurement: usize, mut engine: E) -> Results
+  where E: FnMut(Parameters) -> Results {
   let cmd_args = CmdArgs::from_args();
   if cmd_args.production_mode {
     ::simple_logger::init().expect("Failed to initialize logger");
   } else {
     println!("Running ergothic simulation \"{}\".", name);
   }
-  let parameters = construct_parameters(name.to_string(), reg.freeze(),
-                                        cmd_args);
-  ::simulation::run(parameters, measure_fn);
+  let sweep_interval = cmd_args.sweep_interval_secs.map(Duration::from_secs);
+  if sweep_interval.is_some() && cmd_args.sweep.is_empty() {
+    panic!("Parent argument --sweep is required by --sweep_interval_secs.");
+  }
+  let sweep = Sweep::parse(&cmd_args.sweep, sweep_interval);
+  let results = match sweep {
+    Some(ref sweep) if sweep.interval.is_some() =>
+      run_round_robin(name, reg.freeze(), mutations_per_measurement, cmd_args,
+                      sweep, &mut engine),
+    _ => {
+      let node_index = cmd_args.node_index;
+      let mut parameters = construct_parameters(
+        name.to_string(), reg.freeze(), mutations_per_measurement, cmd_args);
+      if let Some(ref sweep) = sweep {
+        sweep.apply(node_index, &mut parameters);
+      }
+      engine(parameters)
+    },
+  };
+  if let Some((code, message)) = exit_status(&results) {
+    error!("{}", message);
+    ::std::process::exit(code);
+  }
+  results
+}
+
+/// Gives the status the process exits with once the simulation stops with
+/// the `results`, along with the reason, or `None` if the process shouldn't
+/// exit.
+pub fn exit_status(results: &Results) -> Option<(i32, &'static str)> {
+  if !results.flushed {
+    // Exit with a distinct status code so that the loss of data is visible to
+    // the cluster scheduler.
+    Some((EXIT_CODE_NOT_FLUSHED,
+          "Exiting without flushing all of the measured values."))
+  } else if results.stop_reason == StopReason::Panicked {
+    Some((EXIT_CODE_PANICKED, "Exiting after a panic."))
+  } else {
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::parse_target;
+  use ::stop::Uncertainty;
+
+  #[test]
+  fn target_uncertainty() {
+    let target = parse_target("Mean X=0.001", Uncertainty::Absolute);
+    assert_eq!(target.measure, "Mean X");
+    match target.uncertainty {
+      Uncertainty::Absolute(uncertainty) => assert_eq!(uncertainty, 0.001),
+      Uncertainty::Relative(_) => panic!("Expected an absolute uncertainty."),
+    }
+  }
+
+  #[test]
+  fn measure_name_may_contain_equality_sign() {
+    let target = parse_target("a=b= 0.5 ", Uncertainty::Relative);
+    assert_eq!(target.measure, "a=b");
+    match target.uncertainty {
+      Uncertainty::Relative(uncertainty) => assert_eq!(uncertainty, 0.5),
+      Uncertainty::Absolute(_) => panic!("Expected a relative uncertainty."),
+    }
+  }
+
+  #[test]
+  #[should_panic(expected = "Expected <measure name>=<uncertainty>")]
+  fn target_without_uncertainty() {
+    parse_target("Mean X", Uncertainty::Absolute);
+  }
+
+  #[test]
+  #[should_panic(expected = "Failed to parse the uncertainty")]
+  fn target_with_invalid_uncertainty() {
+    parse_target("Mean X=small", Uncertainty::Absolute);
+  }
 }
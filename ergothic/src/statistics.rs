@@ -0,0 +1,43 @@
+use ::measure::Measures;
+
+/// Receives the statistics of the update algorithm reported by the sample,
+/// such as the acceptance rate of the Metropolis steps, and records them as
+/// diagnostic measures. See `Sample::report_statistics`.
+pub struct UpdateStats<'a> {
+  /// Measures the statistics are recorded in. Statistics reported right after
+  /// the thermalization are discarded.
+  measures: Option<&'a mut Measures>,
+}
+
+impl<'a> UpdateStats<'a> {
+  /// Records the statistics in `measures`.
+  pub fn new(measures: &'a mut Measures) -> UpdateStats<'a> {
+    UpdateStats {
+      measures: Some(measures),
+    }
+  }
+
+  /// Discards the statistics. Handed to the sample once it has been
+  /// thermalized, so that the statistics of the thermalization are dropped.
+  pub fn after_thermalization() -> UpdateStats<'a> {
+    UpdateStats {
+      measures: None,
+    }
+  }
+
+  /// Returns true if the sample has just been thermalized, and the measurement
+  /// is about to begin. Update algorithms tuned during the thermalization
+  /// should stop adapting, so that the measured samples satisfy detailed
+  /// balance.
+  pub fn thermalization_finished(&self) -> bool {
+    self.measures.is_none()
+  }
+
+  /// Records the `value` in the diagnostic measure `__ergothic/<name>`.
+  pub fn record(&mut self, name: &str, value: f64) {
+    if let Some(ref mut measures) = self.measures {
+      let idx = measures.find_or_register_diagnostic(name);
+      measures.accumulate(idx, value);
+    }
+  }
+}
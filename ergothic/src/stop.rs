@@ -0,0 +1,310 @@
+use ::measure::Measures;
+use ::std::sync::Once;
+use ::std::sync::atomic::AtomicBool;
+use ::std::sync::atomic::Ordering;
+use ::std::time::Duration;
+
+/// Set by the handler of SIGINT and SIGTERM.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Guards the installation of the signal handlers, which can only happen once
+/// per process.
+static INSTALL_SIGNAL_HANDLERS: Once = Once::new();
+
+/// Installs handlers for SIGINT and SIGTERM. The first signal asks the
+/// simulation to stop gracefully. A second signal terminates the process
+/// immediately.
+pub fn install_signal_handlers() {
+  INSTALL_SIGNAL_HANDLERS.call_once(|| {
+    let result = ::ctrlc::set_handler(|| {
+      if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        error!("Received a second termination signal, exiting immediately.");
+        ::std::process::exit(130);
+      }
+      warn!("Received a termination signal, stopping the simulation.");
+    });
+    if let Err(err) = result {
+      warn!("Failed to install signal handlers: {:?}", err);
+    }
+  });
+}
+
+/// Returns true if the process has received SIGINT or SIGTERM.
+pub fn interrupted() -> bool {
+  INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Statistical uncertainty that a measure should reach before the simulation
+/// stops.
+#[derive(Clone, Debug)]
+pub enum Uncertainty {
+  /// Absolute statistical uncertainty.
+  Absolute(f64),
+
+  /// Statistical uncertainty relative to the absolute value of the expectation.
+  Relative(f64),
+}
+
+/// Target statistical uncertainty for a specific measure.
+#[derive(Clone, Debug)]
+pub struct UncertaintyTarget {
+  /// The name of the measure.
+  pub measure: String,
+
+  /// The uncertainty the measure should reach.
+  pub uncertainty: Uncertainty,
+}
+
+impl UncertaintyTarget {
+  /// Returns true if the target is reached by the accumulated `measures`. A
+  /// measure which hasn't been recorded at least twice never reaches its
+  /// target.
+  fn reached(&self, measures: &Measures) -> bool {
+    let measure = match measures.slice().iter()
+                                .find(|m| m.name == self.measure) {
+      Some(measure) => measure,
+      None => return false,
+    };
+    if measure.acc.num_of_samples() < 2.0 {
+      return false;
+    }
+    let uncertainty = measure.acc.uncertainty();
+    match self.uncertainty {
+      Uncertainty::Absolute(target) => uncertainty <= target,
+      Uncertainty::Relative(target) =>
+        uncertainty <= target * measure.acc.value().abs(),
+    }
+  }
+}
+
+/// Conditions under which the simulation stops. By default, the simulation
+/// never stops.
+#[derive(Clone, Debug, Default)]
+pub struct StopCriteria {
+  /// Stop after drawing this many samples, counted over all chains.
+  pub max_samples: Option<usize>,
+
+  /// Stop after running for this long, including preparation and
+  /// thermalization.
+  pub max_duration: Option<Duration>,
+
+  /// Stop once all of the measures reach their target uncertainties. The
+  /// targets are checked on every flush.
+  pub targets: Vec<UncertaintyTarget>,
+}
+
+impl StopCriteria {
+  /// Checks the limits on the number of samples and on the running time, and
+  /// whether the process has been interrupted by a signal.
+  pub fn limit_reached(&self, samples: usize, elapsed: Duration)
+         -> Option<StopReason> {
+    if interrupted() {
+      return Some(StopReason::Interrupted);
+    }
+    if let Some(max_samples) = self.max_samples {
+      if samples >= max_samples {
+        return Some(StopReason::MaxSamples);
+      }
+    }
+    if let Some(max_duration) = self.max_duration {
+      if elapsed >= max_duration {
+        return Some(StopReason::MaxDuration);
+      }
+    }
+    None
+  }
+
+  /// Checks whether all of the target uncertainties are reached by the
+  /// accumulated `measures`. Always false if there are no targets.
+  pub fn targets_reached(&self, measures: &Measures) -> bool {
+    !self.targets.is_empty() &&
+      self.targets.iter().all(|target| target.reached(measures))
+  }
+
+  /// Warns about the targets naming measures which are missing from
+  /// `measures`, such as misspelled ones. Measures can be registered while
+  /// the simulation is running, so such targets are kept, but they are never
+  /// reached unless their measures appear.
+  pub fn check_targets(&self, measures: &Measures) {
+    for name in self.unknown_targets(measures) {
+      warn!("Target uncertainty is set for measure '{}', which isn't \
+             registered. The target is never reached unless the measure is \
+             registered while the simulation is running.", name);
+    }
+  }
+
+  /// Gives the names of the targeted measures missing from `measures`.
+  fn unknown_targets(&self, measures: &Measures) -> Vec<&str> {
+    self.targets.iter()
+      .filter(|target| measures.find(&target.measure).is_none())
+      .map(|target| target.measure.as_str())
+      .collect()
+  }
+}
+
+/// The reason the simulation stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+  /// Reached `StopCriteria::max_samples`.
+  MaxSamples,
+
+  /// Reached `StopCriteria::max_duration`.
+  MaxDuration,
+
+  /// All measures reached their target uncertainties.
+  TargetUncertainty,
+
+  /// The process received SIGINT or SIGTERM.
+  Interrupted,
+
+  /// The sample or the measurement function panicked, and the panic policy
+  /// is `PanicPolicy::Exit`.
+  Panicked,
+}
+
+/// Expectation value of a single measure accumulated over the whole run.
+#[derive(Clone, Debug)]
+pub struct MeasureResult {
+  /// The name of the measure.
+  pub name: String,
+
+  /// The mean value approximating the expectation value of the observable.
+  pub value: f64,
+
+  /// The statistical uncertainty of `value`.
+  pub uncertainty: f64,
+
+  /// The number of recorded samples.
+  pub count: f64,
+}
+
+/// Returned by the simulation once it stops. Contains the values accumulated by
+/// this process over the whole run, exported or not.
+#[derive(Clone, Debug)]
+pub struct Results {
+  /// The reason the simulation stopped.
+  pub stop_reason: StopReason,
+
+  /// True if all of the accumulated values have been exported. False if the
+  /// final flush failed.
+  pub flushed: bool,
+
+  /// Accumulated values of all measures, including the diagnostic ones.
+  pub measures: Vec<MeasureResult>,
+}
+
+impl Results {
+  /// Constructs results from the accumulated `measures`.
+  pub fn new(stop_reason: StopReason, flushed: bool, measures: &Measures)
+         -> Results {
+    Results {
+      stop_reason,
+      flushed,
+      measures: measures.slice().iter()
+        .map(|measure| MeasureResult {
+          name: measure.name.clone(),
+          value: measure.acc.value(),
+          uncertainty: measure.acc.uncertainty(),
+          count: measure.acc.num_of_samples(),
+        })
+        .collect(),
+    }
+  }
+
+  /// Lookup of the result by the name of the measure.
+  pub fn get(&self, name: &str) -> Option<&MeasureResult> {
+    self.measures.iter().find(|m| m.name == name)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::StopCriteria;
+  use super::StopReason;
+  use super::Uncertainty;
+  use super::UncertaintyTarget;
+  use ::measure::Measures;
+  use ::std::time::Duration;
+
+  /// Measures with a single measure `x` recording the `values`.
+  fn measures(values: &[f64]) -> Measures {
+    let mut measures = Measures::new_empty();
+    let idx = measures.find_or_register("x");
+    for value in values.iter() {
+      measures.accumulate(idx, *value);
+    }
+    measures
+  }
+
+  fn target(measure: &str, uncertainty: Uncertainty) -> UncertaintyTarget {
+    UncertaintyTarget {
+      measure: measure.to_string(),
+      uncertainty,
+    }
+  }
+
+  #[test]
+  fn limits() {
+    let stop = StopCriteria {
+      max_samples: Some(100),
+      max_duration: Some(Duration::from_secs(10)),
+      targets: Vec::new(),
+    };
+    assert_eq!(stop.limit_reached(99, Duration::from_secs(9)), None);
+    assert_eq!(stop.limit_reached(100, Duration::from_secs(9)),
+               Some(StopReason::MaxSamples));
+    assert_eq!(stop.limit_reached(0, Duration::from_secs(10)),
+               Some(StopReason::MaxDuration));
+    assert_eq!(StopCriteria::default()
+                 .limit_reached(usize::MAX, Duration::from_secs(1000000)),
+               None);
+  }
+
+  #[test]
+  fn no_targets_are_never_reached() {
+    assert!(!StopCriteria::default().targets_reached(&measures(&[1.0; 10])));
+  }
+
+  #[test]
+  fn absolute_and_relative_targets() {
+    // The uncertainty of the mean of 1, 3, 1, 3 is 0.5.
+    let measures = measures(&[1.0, 3.0, 1.0, 3.0]);
+    let reached = |uncertainty| StopCriteria {
+      targets: vec![target("x", uncertainty)],
+      ..StopCriteria::default()
+    }.targets_reached(&measures);
+    assert!(reached(Uncertainty::Absolute(0.6)));
+    assert!(!reached(Uncertainty::Absolute(0.4)));
+    assert!(reached(Uncertainty::Relative(0.3)));
+    assert!(!reached(Uncertainty::Relative(0.2)));
+  }
+
+  #[test]
+  fn all_targets_should_be_reached() {
+    let stop = StopCriteria {
+      targets: vec![target("x", Uncertainty::Absolute(1.0)),
+                    target("y", Uncertainty::Absolute(1.0))],
+      ..StopCriteria::default()
+    };
+    assert!(!stop.targets_reached(&measures(&[1.0, 3.0, 1.0, 3.0])));
+  }
+
+  #[test]
+  fn unknown_targets() {
+    let stop = StopCriteria {
+      targets: vec![target("x", Uncertainty::Absolute(1.0)),
+                    target("NoSuchMeasure", Uncertainty::Relative(0.1))],
+      ..StopCriteria::default()
+    };
+    assert_eq!(stop.unknown_targets(&measures(&[])), vec!["NoSuchMeasure"]);
+  }
+
+  #[test]
+  fn single_sample_never_reaches_target() {
+    let stop = StopCriteria {
+      targets: vec![target("x", Uncertainty::Absolute(1.0))],
+      ..StopCriteria::default()
+    };
+    assert!(!stop.targets_reached(&measures(&[1.0])));
+  }
+}
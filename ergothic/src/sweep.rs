@@ -0,0 +1,219 @@
+use ::serde_json::Value;
+use ::simulation::Parameters;
+use ::std::time::Duration;
+
+/// A grid of points in the space of parameters of the sample. Each point is
+/// simulated as if its values were given with `--param`.
+#[derive(Clone, Debug)]
+pub struct Sweep {
+  /// Swept parameters with their values. The points of the sweep are all
+  /// combinations of the values, the last parameter changing fastest.
+  axes: Vec<(String, Vec<Value>)>,
+
+  /// If given, each node cycles through the points, spending this long at
+  /// each of them. Otherwise, each node stays at the point chosen by its
+  /// index.
+  pub interval: Option<Duration>,
+}
+
+/// Maximum number of values in a range of a swept parameter.
+const MAX_RANGE_LEN: usize = 1_000_000;
+
+/// Gives the number of digits after the decimal point needed to write the
+/// number `s` without an exponent, such as 3 for both `0.001` and `1e-3`.
+fn decimals(s: &str) -> usize {
+  let (mantissa, exponent) = match s.find(['e', 'E']) {
+    Some(pos) => (&s[..pos], s[pos + 1..].parse::<i64>().unwrap_or(0)),
+    None => (s, 0),
+  };
+  let digits = mantissa.find('.').map_or(0, |pos| mantissa.len() - pos - 1);
+  ::std::cmp::max(digits as i64 - exponent, 0) as usize
+}
+
+/// Parses a single value of a swept parameter. The value is parsed as JSON, or
+/// taken as a string if it isn't valid JSON.
+fn parse_value(s: &str) -> Value {
+  ::serde_json::from_str(s).unwrap_or_else(|_| Value::String(s.to_string()))
+}
+
+/// Parses the values of a swept parameter, given as `<start>:<stop>:<step>`
+/// or `<value>,<value>,...`. The values of a range are rounded to the number
+/// of decimals used in its `start` and `step`, taking their exponents into
+/// account, and are integers if neither has any decimals.
+fn parse_values(spec: &str) -> Result<Vec<Value>, String> {
+  let bounds: Vec<&str> = spec.split(':').map(|s| s.trim()).collect();
+  if bounds.len() == 1 {
+    return Ok(spec.split(',').map(|s| parse_value(s.trim())).collect());
+  }
+  if bounds.len() != 3 {
+    return Err(format!("Expected <start>:<stop>:<step>, found '{}'.", spec));
+  }
+  let parse = |s: &str| s.parse::<f64>()
+    .map_err(|_| format!("Failed to parse '{}' in '{}'.", s, spec));
+  let (start, stop, step) = (parse(bounds[0])?, parse(bounds[1])?,
+                             parse(bounds[2])?);
+  if !start.is_finite() || !stop.is_finite() || !step.is_finite() {
+    return Err(format!("Range '{}' has non-finite bounds.", spec));
+  }
+  if step <= 0.0 || stop < start {
+    return Err(format!("Range '{}' is empty.", spec));
+  }
+  let digits = ::std::cmp::max(decimals(bounds[0]), decimals(bounds[2]));
+  // Tolerate the rounding errors accumulated in the last point.
+  let count = ((stop - start) / step + 1e-9).floor() + 1.0;
+  if count > MAX_RANGE_LEN as f64 {
+    return Err(format!("Range '{}' has more than {} values.", spec,
+                       MAX_RANGE_LEN));
+  }
+  let count = count as usize;
+  Ok((0..count)
+    .map(|i| {
+      let value = format!("{:.*}", digits, start + i as f64 * step);
+      ::serde_json::from_str(&value).unwrap()
+    })
+    .collect())
+}
+
+impl Sweep {
+  /// Parses the swept parameters given as `<name>=<values>`, see
+  /// `parse_values(..)`. Returns `None` if no parameters are swept. Panics on
+  /// errors.
+  pub fn parse(specs: &[String], interval: Option<Duration>) -> Option<Sweep> {
+    if specs.is_empty() {
+      return None;
+    }
+    let axes = specs.iter()
+      .map(|spec| {
+        let pos = spec.find('=').unwrap_or_else(
+          || panic!("Expected <name>=<values>, found '{}'.", spec));
+        let values = parse_values(&spec[pos + 1..]).unwrap_or_else(
+          |err| panic!("Failed to parse --sweep {}: {}", spec, err));
+        (spec[..pos].to_string(), values)
+      })
+      .collect();
+    Some(Sweep {
+      axes,
+      interval,
+    })
+  }
+
+  /// Gives the number of points in the sweep.
+  pub fn num_points(&self) -> usize {
+    self.axes.iter().map(|(_, values)| values.len()).product()
+  }
+
+  /// Gives the values of the swept parameters at the `index`-th point.
+  fn point(&self, mut index: usize) -> ::serde_json::Map<String, Value> {
+    let mut point = ::serde_json::Map::new();
+    for (name, values) in self.axes.iter().rev() {
+      point.insert(name.clone(), values[index % values.len()].clone());
+      index /= values.len();
+    }
+    point
+  }
+
+  /// Sets the parameters of the sample to the values at the `index`-th point,
+  /// overriding the ones given with `--param` and `--params_file`. The index
+  /// of the point is recorded in every exported data point.
+  pub fn apply(&self, index: u64, parameters: &mut Parameters) {
+    let index = (index % self.num_points() as u64) as usize;
+    let point = self.point(index);
+    info!("Simulating sweep point {} of {}: {}.", index, self.num_points(),
+          Value::Object(point.clone()));
+    let mut params = match parameters.params.take() {
+      Some(Value::Object(params)) => params,
+      _ => ::serde_json::Map::new(),
+    };
+    params.extend(point);
+    parameters.params = Some(Value::Object(params));
+    parameters.metadata.sweep_point = Some(index);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Sweep;
+  use super::parse_values;
+  use ::serde_json::Value;
+
+  fn values(spec: &str) -> Vec<Value> {
+    parse_values(spec).unwrap()
+  }
+
+  fn json(s: &str) -> Vec<Value> {
+    ::serde_json::from_str(s).unwrap()
+  }
+
+  #[test]
+  fn list_of_values() {
+    assert_eq!(values("1, 2.5,x,true"), json(r#"[1, 2.5, "x", true]"#));
+    assert_eq!(values("0.25"), json("[0.25]"));
+  }
+
+  #[test]
+  fn integer_range() {
+    assert_eq!(values("0:10:2"), json("[0, 2, 4, 6, 8, 10]"));
+    assert_eq!(values("1:6:2"), json("[1, 3, 5]"));
+  }
+
+  #[test]
+  fn float_range_includes_endpoint() {
+    // (0.7 - 0.1) / 0.1 falls slightly short of 6.
+    assert_eq!(values("0.1:0.7:0.1"),
+               json("[0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7]"));
+    assert_eq!(values("0:1:0.25"), json("[0.0, 0.25, 0.5, 0.75, 1.0]"));
+    assert_eq!(values("0:1:0.3"), json("[0.0, 0.3, 0.6, 0.9]"));
+    assert_eq!(values("2.0:2.0:1"), json("[2.0]"));
+  }
+
+  #[test]
+  fn scientific_notation() {
+    assert_eq!(values("1e-3:5e-3:1e-3"),
+               json("[0.001, 0.002, 0.003, 0.004, 0.005]"));
+    assert_eq!(values("2.5E-1:0.75:0.25"), json("[0.25, 0.5, 0.75]"));
+    assert_eq!(values("1e3:3e3:1e3"), json("[1000, 2000, 3000]"));
+    assert_eq!(values("1.5e1:20:2.5"), json("[15.0, 17.5, 20.0]"));
+  }
+
+  #[test]
+  fn non_finite_or_huge_ranges() {
+    assert!(parse_values("0:inf:1").is_err());
+    assert!(parse_values("-inf:0:1").is_err());
+    assert!(parse_values("0:1:NaN").is_err());
+    assert!(parse_values("0:1e300:1").is_err());
+  }
+
+  #[test]
+  fn invalid_ranges() {
+    assert!(parse_values("1:2").is_err());
+    assert!(parse_values("1:2:3:4").is_err());
+    assert!(parse_values("a:2:1").is_err());
+    assert!(parse_values("2:1:1").is_err());
+    assert!(parse_values("0:1:0").is_err());
+    assert!(parse_values("0:1:-0.5").is_err());
+  }
+
+  #[test]
+  fn points_enumerate_the_grid() {
+    let specs = vec!["a=1,2".to_string(), "b=0:0.2:0.1".to_string()];
+    let sweep = Sweep::parse(&specs, None).unwrap();
+    assert_eq!(sweep.num_points(), 6);
+    let points: Vec<Value> = (0..6)
+      .map(|i| Value::Object(sweep.point(i)))
+      .collect();
+    assert_eq!(points, json(r#"[{"a": 1, "b": 0.0}, {"a": 1, "b": 0.1},
+                                {"a": 1, "b": 0.2}, {"a": 2, "b": 0.0},
+                                {"a": 2, "b": 0.1}, {"a": 2, "b": 0.2}]"#));
+  }
+
+  #[test]
+  fn no_sweep() {
+    assert!(Sweep::parse(&[], None).is_none());
+  }
+
+  #[test]
+  #[should_panic(expected = "Expected <name>=<values>")]
+  fn missing_name() {
+    Sweep::parse(&["1,2".to_string()], None);
+  }
+}
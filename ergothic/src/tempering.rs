@@ -0,0 +1,143 @@
+use ::equilibrate::Thermalizer;
+use ::measure::MeasureIdx;
+use ::measure::Measures;
+use ::rng::Rng;
+use ::simulation::Chain;
+use ::simulation::ParameterizedSample;
+
+/// A sample whose distribution depends on a coupling, such as the inverse
+/// temperature β. Required for parallel tempering, in which replicas of the
+/// sample are simulated at different couplings and swapped between them.
+pub trait TemperedSample: ParameterizedSample {
+  /// Sets the coupling at which the sample is simulated. Subsequent mutations
+  /// must satisfy detailed balance with respect to the distribution at the new
+  /// coupling.
+  fn set_coupling(&mut self, coupling: f64);
+
+  /// Gives the action of the configuration at the given `coupling`, so that
+  /// its statistical weight is `exp(-action_at(coupling))`. For a spin system
+  /// at the inverse temperature β, this is β times the energy.
+  fn action_at(&self, coupling: f64) -> f64;
+}
+
+/// A replica of the sample simulated at a fixed coupling. The values measured
+/// at different couplings are kept apart.
+struct Replica<S> {
+  coupling: f64,
+  chain: Chain<S>,
+  measures: Measures,
+
+  /// Acceptance rate of the swaps with the replica at the next coupling. The
+  /// replica at the last coupling has no next one.
+  swap_acceptance: Option<MeasureIdx>,
+}
+
+/// Replicas of the sample at different couplings, which periodically attempt
+/// to swap their configurations. Configurations stuck in a metastable state at
+/// one coupling can escape it by travelling to a coupling at which the
+/// barriers are lower.
+pub struct Ensemble<S> {
+  replicas: Vec<Replica<S>>,
+
+  /// Generator deciding on the swaps.
+  rng: Rng,
+
+  /// Swaps are attempted between replicas `(k, k + 1)` for even `k` and for
+  /// odd `k` on alternate steps.
+  parity: usize,
+}
+
+impl<S: TemperedSample> Ensemble<S> {
+  /// Prepares and thermalizes a replica at each of the `couplings`, using the
+  /// corresponding generators from `rngs`. The replicas record the
+  /// `measures`. Swaps are decided with the generator `rng`. Panics if fewer
+  /// than two couplings are given, or if the couplings are not distinct.
+  pub fn new(couplings: &[f64], measures: &Measures, rngs: Vec<Rng>, rng: Rng,
+             thermalizer: &Thermalizer<S>) -> Ensemble<S> {
+    if couplings.len() < 2 {
+      panic!("Parallel tempering requires at least two couplings.");
+    }
+    for (i, coupling) in couplings.iter().enumerate() {
+      if couplings[..i].contains(coupling) {
+        panic!("Coupling {} is given more than once.", coupling);
+      }
+    }
+    let replicas = couplings.iter().zip(rngs).enumerate()
+      .map(|(i, (&coupling, mut rng))| {
+        let mut sample = S::prepare(&thermalizer.params, &mut rng);
+        sample.set_coupling(coupling);
+        let thermalization = thermalizer.thermalize(&mut sample, &mut rng);
+        let mut measures = measures.clone();
+        let swap_acceptance = if i + 1 < couplings.len() {
+          Some(measures.find_or_register_diagnostic("swap_acceptance"))
+        } else {
+          None
+        };
+        Replica {
+          coupling,
+          chain: Chain::new(sample, rng, thermalization, &mut measures),
+          measures,
+          swap_acceptance,
+        }
+      })
+      .collect();
+    Ensemble {
+      replicas,
+      rng,
+      parity: 0,
+    }
+  }
+
+  /// Draws the next sample at each of the couplings, recording the values of
+  /// observables in the measures of the corresponding replicas, and then
+  /// attempts to swap the configurations of neighbouring replicas.
+  pub fn step<F>(&mut self, mutations: usize, measure_fn: &mut F)
+    where F: FnMut(&S, &mut Measures) {
+    for replica in self.replicas.iter_mut() {
+      replica.chain.step(mutations, &mut replica.measures, measure_fn);
+    }
+    self.swap();
+  }
+
+  /// Attempts to swap the configurations of every other pair of neighbouring
+  /// replicas. A swap is accepted with the probability
+  /// `min(1, exp(S_k(x_k) + S_l(x_l) - S_k(x_l) - S_l(x_k)))`, where `S_k` is
+  /// the action at the `k`-th coupling, which preserves detailed balance of
+  /// the joint distribution of all replicas.
+  fn swap(&mut self) {
+    use ::rand::Rng;
+    let mut k = self.parity;
+    self.parity = 1 - self.parity;
+    while k + 1 < self.replicas.len() {
+      let (lower, upper) = self.replicas.split_at_mut(k + 1);
+      let (lower, upper) = (&mut lower[k], &mut upper[0]);
+      let log_ratio =
+        lower.chain.sample().action_at(lower.coupling) +
+        upper.chain.sample().action_at(upper.coupling) -
+        lower.chain.sample().action_at(upper.coupling) -
+        upper.chain.sample().action_at(lower.coupling);
+      let accepted =
+        log_ratio >= 0.0 || self.rng.gen::<f64>() < log_ratio.exp();
+      if accepted {
+        ::std::mem::swap(lower.chain.sample_mut(), upper.chain.sample_mut());
+        lower.chain.sample_mut().set_coupling(lower.coupling);
+        upper.chain.sample_mut().set_coupling(upper.coupling);
+      }
+      if let Some(swap_acceptance) = lower.swap_acceptance {
+        lower.measures.accumulate(swap_acceptance,
+                                  if accepted { 1.0 } else { 0.0 });
+      }
+      k += 2;
+    }
+  }
+
+  /// Moves the values measured by the replicas to `measures`. The name of
+  /// every measure is given the suffix `@<coupling>`.
+  pub fn collect(&mut self, measures: &mut Measures) {
+    for replica in self.replicas.iter_mut() {
+      measures.merge_suffixed(&replica.measures,
+                              &format!("@{}", replica.coupling));
+      replica.measures.reset();
+    }
+  }
+}
@@ -0,0 +1,156 @@
+//! Custom derive for declaring sets of measures in *ergothic* simulations.
+//! Deriving `Measures` for a plain struct implements `ergothic::MeasureValue`
+//! for it: every field of the struct becomes one or more registered measures,
+//! and filling the struct in the measurement function is enough to accumulate
+//! all of them.
+//!
+//! ```ignore
+//! #[derive(Default, Measures)]
+//! struct Observables {
+//!   #[measure(name = "Mean X")]
+//!   x: f64,
+//!   powers: [f64; 10],
+//! }
+//! ```
+//!
+//! By default, the name of the measure is the name of the field. It can be
+//! overridden with the `#[measure(name = "...")]` attribute. Elements of
+//! arrays are called `name[i]`, unless the name contains a `{}` placeholder
+//! for the index, as in `#[measure(name = "G({})")]`. A malformed `#[measure]`
+//! attribute is a compile error.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+extern crate syn;
+
+#[macro_use]
+extern crate quote;
+
+use proc_macro::TokenStream;
+
+#[proc_macro_derive(Measures, attributes(measure))]
+pub fn derive_measures(input: TokenStream) -> TokenStream {
+  let input: syn::DeriveInput = syn::parse(input)
+    .expect("#[derive(Measures)] failed to parse the input.");
+  match expand(&input) {
+    Ok(expanded) => expanded.into(),
+    Err(err) => err.to_compile_error().into(),
+  }
+}
+
+/// Implements `MeasureValue` for the struct described by `input`.
+fn expand(input: &syn::DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+  let ident = &input.ident;
+  let (impl_generics, ty_generics, where_clause) =
+    input.generics.split_for_impl();
+
+  let fields = match input.data {
+    syn::Data::Struct(syn::DataStruct {
+      fields: syn::Fields::Named(ref fields), ..
+    }) => &fields.named,
+    _ => return Err(syn::Error::new_spanned(
+      ident, "#[derive(Measures)] is only supported for structs with named \
+              fields.")),
+  };
+
+  let mut names = Vec::new();
+  let mut values = Vec::new();
+  for field in fields.iter() {
+    let field_ident = field.ident.as_ref().unwrap();
+    let field_ty = &field.ty;
+    let measure_name = measure_name(field)?
+      .unwrap_or_else(|| field_ident.to_string());
+    names.push(quote! {
+      <#field_ty as ::ergothic::MeasureValue>::measure_names(
+        &::ergothic::nested_measure_name(name, #measure_name), names);
+    });
+    values.push(quote! {
+      ::ergothic::MeasureValue::measure_values(&self.#field_ident, f);
+    });
+  }
+
+  Ok(quote! {
+    impl #impl_generics ::ergothic::MeasureValue for #ident #ty_generics
+        #where_clause {
+      fn measure_names(name: &str, names: &mut Vec<String>) {
+        #(#names)*
+      }
+
+      fn measure_values(&self, f: &mut dyn FnMut(f64)) {
+        #(#values)*
+      }
+    }
+  })
+}
+
+/// Extracts the measure name from the `#[measure(name = "...")]` attribute of
+/// the field, if present. A malformed attribute is an error, so that a typo
+/// doesn't silently rename the measure.
+fn measure_name(field: &syn::Field) -> syn::Result<Option<String>> {
+  const EXPECTED: &str = "Expected #[measure(name = \"...\")].";
+  let mut name = None;
+  for attr in field.attrs.iter() {
+    if !attr.path.is_ident("measure") {
+      continue;
+    }
+    let list = match attr.parse_meta() {
+      Ok(syn::Meta::List(list)) => list,
+      _ => return Err(syn::Error::new_spanned(attr, EXPECTED)),
+    };
+    for nested in list.nested.iter() {
+      match *nested {
+        syn::NestedMeta::Meta(syn::Meta::NameValue(ref name_value))
+          if name_value.ident == "name" => {
+          if let syn::Lit::Str(ref lit) = name_value.lit {
+            name = Some(lit.value());
+          } else {
+            return Err(syn::Error::new_spanned(&name_value.lit, EXPECTED));
+          }
+        },
+        _ => return Err(syn::Error::new_spanned(
+          nested, "Unknown argument in #[measure(..)] attribute.")),
+      }
+    }
+  }
+  Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::measure_name;
+
+  /// Gives the measure name of the only field of the struct `input`.
+  fn name_of(input: &str) -> Result<Option<String>, String> {
+    let input: syn::DeriveInput = syn::parse_str(input).unwrap();
+    match input.data {
+      syn::Data::Struct(ref data) => {
+        let field = data.fields.iter().next().unwrap();
+        measure_name(field).map_err(|err| err.to_string())
+      },
+      _ => panic!("Expected a struct."),
+    }
+  }
+
+  #[test]
+  fn field_without_attribute() {
+    assert_eq!(name_of("struct S { x: f64 }"), Ok(None));
+    assert_eq!(name_of("struct S { #[serde(skip)] x: f64 }"), Ok(None));
+  }
+
+  #[test]
+  fn named_field() {
+    assert_eq!(name_of("struct S { #[measure(name = \"Mean X\")] x: f64 }"),
+               Ok(Some("Mean X".to_string())));
+  }
+
+  #[test]
+  fn malformed_attributes() {
+    let attrs = ["#[measure]", "#[measure = \"x\"]",
+                 "#[measure(nmae = \"x\")]", "#[measure(name = 1)]",
+                 "#[measure(name)]", "#[measure(name = \"x\" y)]"];
+    for attr in attrs.iter() {
+      let input = format!("struct S {{ {} x: f64 }}", attr);
+      assert!(name_of(&input).is_err(), "{} was accepted.", attr);
+    }
+  }
+}
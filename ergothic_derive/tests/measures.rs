@@ -0,0 +1,77 @@
+extern crate ergothic;
+
+#[macro_use]
+extern crate ergothic_derive;
+
+use ergothic::Complex;
+use ergothic::MeasureValue;
+
+#[derive(Default, Measures)]
+struct Inner {
+  a: f64,
+  #[measure(name = "B")]
+  b: [f32; 2],
+}
+
+#[derive(Default, Measures)]
+struct Observables {
+  #[measure(name = "Mean X")]
+  x: f64,
+  powers: [f64; 2],
+  #[measure(name = "G({})")]
+  g: [f64; 3],
+  grid: [[f64; 2]; 2],
+  #[measure(name = "C({}, {})")]
+  c: [[f64; 2]; 2],
+  z: Complex<f64>,
+  inner: Inner,
+}
+
+/// Gives the names of the measures making up `M`.
+fn names<M: MeasureValue>() -> Vec<String> {
+  let mut names = Vec::new();
+  M::measure_names("", &mut names);
+  names
+}
+
+/// Gives the values of the measures making up `value`.
+fn values<M: MeasureValue>(value: &M) -> Vec<f64> {
+  let mut values = Vec::new();
+  value.measure_values(&mut |v| values.push(v));
+  values
+}
+
+#[test]
+fn measure_names() {
+  assert_eq!(names::<Observables>(),
+             vec!["Mean X", "powers[0]", "powers[1]", "G(0)", "G(1)", "G(2)",
+                  "grid[0][0]", "grid[0][1]", "grid[1][0]", "grid[1][1]",
+                  "C(0, 0)", "C(0, 1)", "C(1, 0)", "C(1, 1)", "Re z", "Im z",
+                  "inner.a", "inner.B[0]", "inner.B[1]"]);
+}
+
+#[test]
+fn measure_values_follow_names() {
+  let observables = Observables {
+    x: 1.0,
+    powers: [2.0, 3.0],
+    g: [4.0, 5.0, 6.0],
+    grid: [[7.0, 8.0], [9.0, 10.0]],
+    c: [[11.0, 12.0], [13.0, 14.0]],
+    z: Complex::new(15.0, 16.0),
+    inner: Inner {
+      a: 17.0,
+      b: [18.0, 19.0],
+    },
+  };
+  let expected: Vec<f64> = (1..20).map(|i| i as f64).collect();
+  assert_eq!(values(&observables), expected);
+  assert_eq!(values(&observables).len(), names::<Observables>().len());
+}
+
+#[test]
+fn nested_names_are_prefixed() {
+  let mut names = Vec::new();
+  Inner::measure_names("outer", &mut names);
+  assert_eq!(names, vec!["outer.a", "outer.B[0]", "outer.B[1]"]);
+}
@@ -1,99 +1,162 @@
 extern crate ergothic;
-extern crate rand;
 
-const N: usize = 30;  // Lattice size.
-const A: f64 = 0.5;   // Lattice spacing.
-const M: f64 = 1.0;   // Oscillator mass.
-const K: f64 = 1.0;   // Oscillator spring tension.
+#[macro_use]
+extern crate ergothic_derive;
 
-// Potential energy V(x).
-fn potential_v(x: f64) -> f64 {
-  K * x.powi(2) / 2.0
+#[macro_use]
+extern crate serde_derive;
+
+use ergothic::metropolis::Metropolis;
+use ergothic::metropolis::Sites;
+use ergothic::metropolis::UniformShift;
+
+// Lattice size. Fixed at compile time, since it determines the number of
+// measures in `Correlators`.
+const N: usize = 30;
+
+// Physical parameters, given on the command line, e.g. --param a=0.25.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct Params {
+  a: f64,  // Lattice spacing.
+  m: f64,  // Oscillator mass.
+  k: f64,  // Oscillator spring tension.
+}
+
+impl Default for Params {
+  fn default() -> Params {
+    Params {
+      a: 0.5,
+      m: 1.0,
+      k: 1.0,
+    }
+  }
 }
 
-// Trajectory of the oscillator: X(t) function.
+// Trajectory of the oscillator: X(t) function. Serializable, so that the
+// thermalized trajectory can be checkpointed.
+#[derive(Serialize, Deserialize)]
 struct Trajectory {
+  params: Params,
   x: Vec<f64>,
 }
 
 impl Trajectory {
+  // Potential energy V(x).
+  fn potential_v(&self, x: f64) -> f64 {
+    self.params.k * x.powi(2) / 2.0
+  }
+
   // Euclidean Lagrangian function for i-th link (between nodes i and i+1).
   fn lagrangian(&self, i: usize) -> f64 {
     assert!(i < N,
             "Trajectory::lagrangian(..): index {} out of range [0, {}).",
             i, N);
     let j = (i + 1) % N;
-    let kinetic = M * (self.x[j] - self.x[i]).powi(2) / (2.0 * A);
-    let potential = A * potential_v((self.x[i] + self.x[j]) / 2.0);
+    let Params { a, m, .. } = self.params;
+    let kinetic = m * (self.x[j] - self.x[i]).powi(2) / (2.0 * a);
+    let potential = a * self.potential_v((self.x[i] + self.x[j]) / 2.0);
     // Euclidean signature, thus "+".
     kinetic + potential
   }
+}
+
+// The nodes of the trajectory are updated one at a time by the Metropolis
+// helpers.
+impl Sites for Trajectory {
+  type Value = f64;
+
+  fn num_sites(&self) -> usize {
+    N
+  }
+
+  fn site(&self, i: usize) -> f64 {
+    self.x[i]
+  }
+
+  fn set_site(&mut self, i: usize, x: f64) {
+    self.x[i] = x;
+  }
 
   // The part of the action that changes when the i-th node is mutated.
-  fn contact_action(&self, i: usize) -> f64 {
+  fn local_action(&self, i: usize) -> f64 {
     self.lagrangian(i) + self.lagrangian((i + N - 1) % N)
   }
+}
 
-  fn randomize(&mut self, n_times: usize) {
-    use rand::distributions::Distribution;
-    let mut rng = rand::prelude::thread_rng();
-    let epsilon = 15.0;
-    let uniform = rand::distributions::Uniform::<f64>
-                      ::new_inclusive(-epsilon, epsilon);
-    let uniform_prob = rand::distributions::Uniform::<f64>
-                           ::new_inclusive(0.0, 1.0);
-    for _ in 0..n_times {
-      for i in 0..N {
-        let old_x = self.x[i];
-        let old_s = self.contact_action(i);
-        self.x[i] = uniform.sample(&mut rng);
-        let new_s = self.contact_action(i);
-        let ds = new_s - old_s;
-        if ds > 0.0 {
-          // Metropolis-Hastings probabilistic step.
-          let eta = uniform_prob.sample(&mut rng);
-          if (-ds).exp() <= eta {
-            // Restore the old value.
-            self.x[i] = old_x;
-          }
-        }
-      }
+// The sample: the trajectory together with the Metropolis helper updating it,
+// which keeps track of the acceptance rate.
+#[derive(Serialize, Deserialize)]
+struct Oscillator {
+  trajectory: Trajectory,
+  metropolis: Metropolis<UniformShift>,
+}
+
+// The random number generator is owned by the simulation engine, so that runs
+// can be reproduced with --seed.
+impl ergothic::ParameterizedSample for Oscillator {
+  type Params = Params;
+
+  fn prepare(params: &Params, _rng: &mut ergothic::Rng) -> Oscillator {
+    Oscillator {
+      trajectory: Trajectory {
+        params: params.clone(),
+        x: vec![0.0; N],
+      },
+      // The initial width is tuned for the acceptance rate of 50% during the
+      // thermalization, and frozen afterwards.
+      metropolis: Metropolis::new(UniformShift { width: 15.0 })
+                    .with_tuning(0.5),
     }
   }
-}
 
-impl ergothic::Sample for Trajectory {
-  fn prepare() -> Trajectory {
-    Trajectory {
-      x: vec![0.0; N],
+  fn thermalize(&mut self, rng: &mut ergothic::Rng) {
+    for _ in 0..500 {
+      self.metropolis.sweep(&mut self.trajectory, rng);
     }
   }
 
-  fn thermalize(&mut self) {
-    self.randomize(500);
+  fn mutate(&mut self, rng: &mut ergothic::Rng) {
+    self.metropolis.sweep(&mut self.trajectory, rng);
+  }
+
+  // Euclidean action of the trajectory. Tracked by the adaptive thermalization
+  // (--adaptive_thermalization) and recorded as a diagnostic measure.
+  fn action(&self) -> Option<f64> {
+    Some((0..N).map(|i| self.trajectory.lagrangian(i)).sum())
   }
 
-  fn mutate(&mut self) {
-    self.randomize(20);
+  // Recorded as the diagnostic measures __ergothic/acceptance and
+  // __ergothic/acceptance/width.
+  fn report_statistics(&mut self, stats: &mut ergothic::UpdateStats) {
+    self.metropolis.report("acceptance", stats);
   }
 }
 
+// Measures recorded for each sample.
+#[derive(Default, Measures)]
+struct Correlators {
+  // g[k] is the mean value of <X_i X_(i+k)> over i and over samples, recorded
+  // as the measure G(k).
+  #[measure(name = "G({})")]
+  g: [f64; N],
+}
+
 fn main() {
   let mut sim = ergothic::Simulation::new("Oscillator");
-  // g[k] is the mean value of <X_i X_(i+k)> over i and over samples.
-  let mut g = Vec::new();
-  for i in 0..N {
-    g.push(sim.add_measure(format!("G({})", i)));
-  }
-  sim.run(|s: &Trajectory, ms| {
+  let correlators = sim.add_measure_set::<Correlators>();
+  sim.enable_checkpoints();
+  // Sweep the lattice 20 times between measurements to reduce autocorrelation.
+  sim.set_mutations_per_measurement(20);
+  sim.run(|s: &Oscillator, ms| {
+    let mut c = Correlators::default();
     for k in 0..N {
-      let mut g_k = 0.0;
       // Computing correlator $g[k] = N^{-1} \sum_i \left< X_i X_{i+k} \right>$.
       for i in 0..N {
-        g_k += s.x[i] * s.x[(i + k) % N];
+        c.g[k] += s.trajectory.x[i] * s.trajectory.x[(i + k) % N];
       }
-      g_k /= N as f64;
-      ms.accumulate(g[k], g_k);
+      c.g[k] /= N as f64;
     }
+    ms.accumulate_set(&correlators, &c);
   });
 }